@@ -1,22 +1,62 @@
-//mod quic;
+mod metrics;
+mod quic;
 mod tcp;
+mod udp;
+use clap::Clap;
 use std::net;
+use std::path::PathBuf;
 
 const MB: usize = 1024 * 1024;
 
+#[derive(Clap)]
+#[clap(about = "Server of nettest")]
+struct Opt {
+    /// Port of TCP, UDP and QUIC server
+    port: u16,
+    /// Certificate chain (PEM) of QUIC server, QUIC is disabled without it
+    #[clap(long, parse(from_os_str), requires = "key")]
+    cert: Option<PathBuf>,
+    /// Private key (PEM) of QUIC server
+    #[clap(long, parse(from_os_str), requires = "cert")]
+    key: Option<PathBuf>,
+    /// Directory to write qlog traces of QUIC connections to
+    #[clap(long, parse(from_os_str))]
+    qlog: Option<PathBuf>,
+    /// Send downloads with sendfile(2) from a memfd (Linux only)
+    #[clap(long)]
+    zerocopy: bool,
+    /// Serve Prometheus metrics at http://<addr>/metrics, e.g. 0.0.0.0:9100
+    #[clap(long)]
+    metrics: Option<net::SocketAddr>,
+}
+
 #[async_std::main]
 async fn main() {
-    let mut args = std::env::args();
-    let cmd = args.next().unwrap();
-    if args.len() != 1 {
-        println!("Usage: `{} [PORT]", cmd);
-        return;
+    let opt = Opt::parse();
+    let socker = net::SocketAddr::new(net::Ipv4Addr::UNSPECIFIED.into(), opt.port);
+    if let Some(addr) = opt.metrics {
+        std::thread::spawn(move || {
+            if let Err(e) = metrics::server(addr) {
+                eprintln!("Metrics Err: {}", e);
+            }
+        });
+    }
+    if let (Some(cert), Some(key)) = (opt.cert, opt.key) {
+        let qlog = opt.qlog;
+        std::thread::spawn(move || {
+            if let Err(e) = quic::server(socker, &cert, &key, qlog.as_deref()) {
+                eprintln!("QUIC Err: {}", e);
+            }
+        });
+    } else {
+        std::thread::spawn(move || {
+            if let Err(e) = udp::server(socker) {
+                eprintln!("UDP Err: {}", e);
+            }
+        });
     }
-    let port: u16 = args.next().unwrap().parse().unwrap();
-    let socker = net::SocketAddr::new(net::Ipv4Addr::UNSPECIFIED.into(), port);
-    //let quic = tokio::spawn(async { quic::server(port).await });
 
-    if let Err(e) = tcp::server(socker).await {
+    if let Err(e) = tcp::server(socker, opt.zerocopy).await {
         eprintln!("Err: {}", e);
     }
 }
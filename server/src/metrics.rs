@@ -0,0 +1,137 @@
+use anyhow::{Context, Result};
+use nettest::http;
+use std::fmt::Write as _;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters of one protocol the server speaks.
+pub struct Counters {
+    /// Connections accepted, load tests for UDP.
+    connections: AtomicU64,
+    closed: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl Counters {
+    const fn new() -> Counters {
+        Counters {
+            connections: AtomicU64::new(0),
+            closed: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    pub fn connect(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn close(&self) {
+        self.closed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub static TCP: Counters = Counters::new();
+pub static UDP: Counters = Counters::new();
+pub static QUIC: Counters = Counters::new();
+
+fn load(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+/// All counters in the Prometheus text format.
+fn render() -> String {
+    render_counters(&[("tcp", &TCP), ("udp", &UDP), ("quic", &QUIC)])
+}
+
+/// `protocols` in the Prometheus text format.
+fn render_counters(protocols: &[(&str, &Counters)]) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, ty: &str, help: &str, value: &dyn Fn(&Counters) -> u64| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, ty);
+        for (protocol, counters) in protocols {
+            let _ = writeln!(
+                out,
+                "{}{{protocol=\"{}\"}} {}",
+                name,
+                protocol,
+                value(counters)
+            );
+        }
+    };
+    metric(
+        "nettest_server_connections_total",
+        "counter",
+        "Connections accepted, load tests for UDP.",
+        &|c: &Counters| load(&c.connections),
+    );
+    metric(
+        "nettest_server_active_connections",
+        "gauge",
+        "Connections open now.",
+        &|c: &Counters| load(&c.connections).saturating_sub(load(&c.closed)),
+    );
+    metric(
+        "nettest_server_sent_bytes_total",
+        "counter",
+        "Bytes served to clients.",
+        &|c: &Counters| load(&c.bytes_sent),
+    );
+    metric(
+        "nettest_server_received_bytes_total",
+        "counter",
+        "Bytes received from clients.",
+        &|c: &Counters| load(&c.bytes_received),
+    );
+    metric(
+        "nettest_server_errors_total",
+        "counter",
+        "Failed requests and packets.",
+        &|c: &Counters| load(&c.errors),
+    );
+    out
+}
+
+/// Serve the counters at `http://<addr>/metrics`, blocks the thread.
+pub fn server(addr: SocketAddr) -> Result<()> {
+    let listener =
+        TcpListener::bind(addr).with_context(|| format!("Failed to listen on {}", addr))?;
+    println!("Metrics server listening on {}", addr);
+    for stream in listener.incoming() {
+        if let Err(e) = stream.and_then(|stream| http::respond(stream, render)) {
+            eprintln!("Metrics request error: {}", e);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn render_connections() {
+    let tcp = Counters::new();
+    tcp.connect();
+    tcp.connect();
+    tcp.close();
+    let text = render_counters(&[("tcp", &tcp), ("udp", &Counters::new())]);
+    assert!(text.contains("# TYPE nettest_server_connections_total counter"));
+    assert!(text.contains("nettest_server_connections_total{protocol=\"tcp\"} 2"));
+    assert!(text.contains("nettest_server_connections_total{protocol=\"udp\"} 0"));
+    assert!(text.contains("# TYPE nettest_server_active_connections gauge"));
+    assert!(text.contains("nettest_server_active_connections{protocol=\"tcp\"} 1"));
+}
@@ -0,0 +1,264 @@
+use super::metrics;
+use super::udp;
+use super::MB;
+use anyhow::{anyhow, Result};
+use nettest::wire;
+use rand::{Rng, SeedableRng};
+use rand_xoshiro::Xoshiro256Plus;
+use ring::hmac;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use std::pin::Pin;
+use std::time::Duration;
+
+const MAX_DATAGRAM_SIZE: usize = 1350;
+
+struct Client {
+    conn: Pin<Box<quiche::Connection>>,
+    peer: SocketAddr,
+    /// Partial request lines, keyed by stream ID.
+    requests: HashMap<u64, Vec<u8>>,
+    /// Bytes left to send, keyed by stream ID.
+    downloads: HashMap<u64, usize>,
+    rng: Xoshiro256Plus,
+}
+
+pub fn server(addr: SocketAddr, cert: &Path, key: &Path, qlog: Option<&Path>) -> Result<()> {
+    let rand_pool: Vec<u8> = {
+        let mut rng = Xoshiro256Plus::from_entropy();
+        let mut buffer = Vec::with_capacity(2 * MB);
+        for _ in 0..2 * MB {
+            buffer.push(rng.gen_range(0x20, 0x7F));
+        }
+        buffer
+    };
+    let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    config.load_cert_chain_from_pem_file(path_str(cert)?)?;
+    config.load_priv_key_from_pem_file(path_str(key)?)?;
+    config.set_application_protos(b"\x13speedtest/0.1")?;
+    config.set_max_idle_timeout(30_000);
+    config.set_max_udp_payload_size(MAX_DATAGRAM_SIZE as u64);
+    config.set_initial_max_data(64 * MB as u64);
+    config.set_initial_max_stream_data_bidi_local(16 * MB as u64);
+    config.set_initial_max_stream_data_bidi_remote(16 * MB as u64);
+    config.set_initial_max_streams_bidi(100);
+    config.set_disable_active_migration(true);
+    config.enable_dgram(true, 1000, 1000);
+    // Accept requests of quicping --0rtt before the handshake completes.
+    config.enable_early_data();
+    let socket = UdpSocket::bind(addr)?;
+    udp::set_recv_tos(&socket)?;
+    println!("QUIC server listening on {}", addr);
+    let rng = ring::rand::SystemRandom::new();
+    let seed = hmac::Key::generate(hmac::HMAC_SHA256, &rng)
+        .map_err(|_| anyhow!("Failed to generate connection ID seed"))?;
+    let mut clients: HashMap<Vec<u8>, Client> = HashMap::new();
+    // nettest UDP tests share the port with QUIC.
+    let mut udp = udp::Server::default();
+    let mut buf = vec![0u8; 65535];
+    let mut out = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let timeout = clients.values().filter_map(|c| c.conn.timeout()).min();
+        if timeout != Some(Duration::from_secs(0)) {
+            socket.set_read_timeout(timeout)?;
+            match udp::recv_from(&socket, &mut buf) {
+                Ok((len, from, tos)) if wire::is_nettest(&buf[..len]) => {
+                    if let Err(e) = udp.handle(&socket, &buf[..len], from, tos) {
+                        metrics::UDP.error();
+                        eprintln!("UDP packet from {} error: {}", from, e);
+                    }
+                }
+                Ok((len, from, _)) => {
+                    metrics::QUIC.received(len);
+                    let pkt = &mut buf[..len];
+                    let result = handle_packet(
+                        &socket,
+                        pkt,
+                        from,
+                        &mut clients,
+                        &mut config,
+                        &seed,
+                        qlog,
+                        &mut out,
+                    );
+                    if let Err(e) = result {
+                        metrics::QUIC.error();
+                        eprintln!("QUIC packet from {} error: {}", from, e);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        // quiche checks by itself whether a timer has expired.
+        clients.values_mut().for_each(|c| c.conn.on_timeout());
+        for client in clients.values_mut() {
+            if let Err(e) = handle_client(client, &rand_pool) {
+                metrics::QUIC.error();
+                eprintln!("QUIC client {} error: {}", client.peer, e);
+                let _ = client.conn.close(false, 0x1, b"");
+            }
+            loop {
+                let write = match client.conn.send(&mut out) {
+                    Ok(v) => v,
+                    Err(quiche::Error::Done) => break,
+                    Err(e) => {
+                        metrics::QUIC.error();
+                        eprintln!("QUIC client {} send error: {}", client.peer, e);
+                        let _ = client.conn.close(false, 0x1, b"");
+                        break;
+                    }
+                };
+                // One unreachable client mustn't stop the others or the UDP tests on this port.
+                match socket.send_to(&out[..write], client.peer) {
+                    Ok(len) => metrics::QUIC.sent(len),
+                    Err(e) => {
+                        metrics::QUIC.error();
+                        eprintln!("QUIC client {} send error: {}", client.peer, e);
+                        break;
+                    }
+                }
+            }
+        }
+        clients.retain(|_, c| {
+            if c.conn.is_closed() {
+                println!("Disconnect with {} (QUIC), {:?}", c.peer, c.conn.stats());
+                metrics::QUIC.close();
+            }
+            !c.conn.is_closed()
+        });
+    }
+}
+
+fn path_str(path: &Path) -> Result<&str> {
+    path.to_str()
+        .ok_or_else(|| anyhow!("Invalid path: {:?}", path))
+}
+
+fn handle_packet(
+    socket: &UdpSocket,
+    pkt: &mut [u8],
+    from: SocketAddr,
+    clients: &mut HashMap<Vec<u8>, Client>,
+    config: &mut quiche::Config,
+    seed: &hmac::Key,
+    qlog: Option<&Path>,
+    out: &mut [u8],
+) -> Result<()> {
+    let hdr = quiche::Header::from_slice(pkt, quiche::MAX_CONN_ID_LEN)?;
+    // Derive our connection ID from the one the client picked, so retransmitted
+    // Initial packets find the same connection.
+    let conn_id = hmac::sign(seed, &hdr.dcid);
+    let conn_id = conn_id.as_ref()[..quiche::MAX_CONN_ID_LEN].to_vec();
+    let key = if clients.contains_key(&hdr.dcid) {
+        hdr.dcid.clone()
+    } else if clients.contains_key(&conn_id) {
+        conn_id
+    } else {
+        if hdr.ty != quiche::Type::Initial {
+            return Err(anyhow!("Packet isn't Initial"));
+        }
+        if !quiche::version_is_supported(hdr.version) {
+            let len = quiche::negotiate_version(&hdr.scid, &hdr.dcid, out)?;
+            metrics::QUIC.sent(socket.send_to(&out[..len], from)?);
+            return Ok(());
+        }
+        let mut conn = quiche::accept(&conn_id, None, config)?;
+        if let Some(dir) = qlog {
+            let id: String = conn_id.iter().map(|b| format!("{:02x}", b)).collect();
+            std::fs::create_dir_all(dir)?;
+            let file = File::create(dir.join(format!("server-{}.qlog", id)))?;
+            conn.set_qlog(
+                Box::new(file),
+                "nettest".to_string(),
+                format!("nettest server connection from {}", from),
+            );
+        }
+        println!("QUIC connection from {}", from);
+        metrics::QUIC.connect();
+        clients.insert(
+            conn_id.clone(),
+            Client {
+                conn,
+                peer: from,
+                requests: HashMap::new(),
+                downloads: HashMap::new(),
+                rng: Xoshiro256Plus::from_entropy(),
+            },
+        );
+        conn_id
+    };
+    let client = clients.get_mut(&key).unwrap();
+    match client.conn.recv(pkt) {
+        Ok(_) | Err(quiche::Error::Done) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn handle_client(client: &mut Client, rand_pool: &[u8]) -> Result<()> {
+    let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+    // Echo DATAGRAM frames back as they are.
+    loop {
+        match client.conn.dgram_recv(&mut buf) {
+            Ok(len) => match client.conn.dgram_send(&buf[..len]) {
+                Ok(()) | Err(quiche::Error::Done) => {}
+                Err(e) => return Err(e.into()),
+            },
+            Err(quiche::Error::Done) => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    for s in client.conn.readable() {
+        while let Ok((len, fin)) = client.conn.stream_recv(s, &mut buf) {
+            let request = client.requests.entry(s).or_default();
+            request.extend_from_slice(&buf[..len]);
+            if !fin && !request.ends_with(b"\n") {
+                continue;
+            }
+            let request = client.requests.remove(&s).unwrap();
+            let request = String::from_utf8_lossy(&request);
+            println!(
+                "Request from {} (QUIC stream {}): {:?}",
+                client.peer, s, request
+            );
+            if request.starts_with("DOWNLOAD ") {
+                let download_bytes: usize = request[8..].trim().parse()?;
+                client.downloads.insert(s, download_bytes);
+            } else {
+                return Err(anyhow!("unknown command"));
+            }
+        }
+    }
+    let mut finished = Vec::new();
+    for (&s, remaining) in client.downloads.iter_mut() {
+        loop {
+            let start = client.rng.gen_range(0, MB);
+            let len = (*remaining).min(MB);
+            let fin = len == *remaining;
+            match client
+                .conn
+                .stream_send(s, &rand_pool[start..start + len], fin)
+            {
+                Ok(written) => {
+                    *remaining -= written;
+                    if *remaining == 0 {
+                        finished.push(s);
+                        break;
+                    }
+                    if written < len {
+                        break;
+                    }
+                }
+                Err(quiche::Error::Done) => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    for s in finished {
+        client.downloads.remove(&s);
+    }
+    Ok(())
+}
@@ -1,20 +1,43 @@
+use super::metrics;
 use super::MB;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context as _, Result};
 use async_std::io::{BufReader, Read};
 use async_std::net::{TcpListener, TcpStream};
 use async_std::prelude::*;
+use nettest::wire::{self, LOAD_DATA, LOAD_SMALL};
+use nettest::{CpuTime, RandPool, TcpInfo};
 use rand::{Rng, SeedableRng};
 use rand_xoshiro::Xoshiro256Plus;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 
-pub async fn server(addr: SocketAddr) -> Result<()> {
-    let rand_pool: Vec<u8> = {
+/// Size of the memfd downloads are sent from with `--zerocopy`.
+const ZEROCOPY_POOL: usize = 64 * MB;
+/// Size of the reply to SMALL.
+const SMALL_SIZE: usize = 1024;
+/// Size of the bulk data frames of LOAD, small objects wait for at most one.
+const LOAD_FRAME: usize = 64 * 1024;
+
+pub async fn server(addr: SocketAddr, zerocopy: bool) -> Result<()> {
+    let rand_pool: Arc<Vec<u8>> = Arc::new({
         let mut rng = Xoshiro256Plus::from_entropy();
         let mut buffer = Vec::with_capacity(2 * MB);
         for _ in 0..2 * MB {
             buffer.push(rng.gen_range(0x20, 0x7F));
         }
         buffer
+    });
+    let zerocopy = if zerocopy {
+        let mut rng = Xoshiro256Plus::from_entropy();
+        let pool = RandPool::new(ZEROCOPY_POOL, |chunk| {
+            for b in chunk {
+                *b = rng.gen_range(0x20, 0x7F);
+            }
+        });
+        Some(Arc::new(pool.context("Failed to create zero-copy pool")?))
+    } else {
+        None
     };
     let listener = TcpListener::bind(addr).await?;
     let mut incoming = listener.incoming();
@@ -26,9 +49,17 @@ pub async fn server(addr: SocketAddr) -> Result<()> {
             }
             Ok(s) => s,
         };
-        if let Err(e) = handle_stream(stream, &rand_pool).await {
-            eprintln!("TCP client handle error: {}", e);
-        }
+        // Serve clients concurrently, tcpbidir opens two connections at once.
+        let rand_pool = Arc::clone(&rand_pool);
+        let zerocopy = zerocopy.clone();
+        metrics::TCP.connect();
+        async_std::task::spawn(async move {
+            if let Err(e) = handle_stream(stream, &rand_pool, zerocopy.as_ref()).await {
+                metrics::TCP.error();
+                eprintln!("TCP client handle error: {}", e);
+            }
+            metrics::TCP.close();
+        });
     }
     Ok(())
 }
@@ -38,14 +69,26 @@ enum HandleState {
     Quit,
 }
 
-async fn handle_stream(mut stream: TcpStream, rand_pool: &[u8]) -> Result<()> {
+async fn handle_stream(
+    mut stream: TcpStream,
+    rand_pool: &[u8],
+    zerocopy: Option<&Arc<RandPool>>,
+) -> Result<()> {
+    // CPU utilisation and TCP_INFO of the last download, reported by the CPU command.
+    let mut last_report = None;
     loop {
         let mut reader = BufReader::new(stream);
         let mut buf = String::new();
-        reader.read_line(&mut buf).await?;
+        let len = reader.read_line(&mut buf).await?;
         stream = reader.into_inner();
-        match handle_inner(&mut stream, &buf, rand_pool).await {
+        metrics::TCP.received(len);
+        if len == 0 {
+            // Closed by the client, e.g. by tcping probes.
+            break;
+        }
+        match handle_inner(&mut stream, &buf, rand_pool, zerocopy, &mut last_report).await {
             Err(e) => {
+                metrics::TCP.error();
                 eprintln!("Tcp client {:?} error: {:?}", stream, e);
                 stream.write_all(b"ERROR\n").await?;
                 break;
@@ -59,13 +102,30 @@ async fn handle_stream(mut stream: TcpStream, rand_pool: &[u8]) -> Result<()> {
     Ok(())
 }
 
-async fn handle_inner(stream: &mut TcpStream, buf: &str, rand_pool: &[u8]) -> Result<HandleState> {
+async fn handle_inner(
+    stream: &mut TcpStream,
+    buf: &str,
+    rand_pool: &[u8],
+    zerocopy: Option<&Arc<RandPool>>,
+    last_report: &mut Option<String>,
+) -> Result<HandleState> {
     println!("Request from {}: {:?}", stream.peer_addr()?, buf);
     match buf {
         _ if buf.split_whitespace().next().is_none() => Ok(HandleState::Next),
         _ if buf.starts_with("QUIT") => Ok(HandleState::Quit),
-        _ if buf.starts_with("DOWNLOAD ") => handle_download(stream, &buf, rand_pool).await,
+        _ if buf.starts_with("DOWNLOAD ") => {
+            let cpu = CpuTime::now();
+            let state = handle_download(stream, &buf, rand_pool, zerocopy).await?;
+            let mut report = cpu.usage().reply();
+            // The sender knows best where the download was limited.
+            if let Ok(info) = TcpInfo::get(&*stream) {
+                report = format!("{} {}", report, info.reply());
+            }
+            *last_report = Some(report);
+            Ok(state)
+        }
         _ if buf.starts_with("UPLOAD ") => handle_upload(stream, &buf).await,
+        _ if buf.starts_with("LOAD") => handle_load(stream, rand_pool).await,
         _ if buf.starts_with("GETIP") => {
             let peer_ip = stream.peer_addr()?.ip();
             let resp = format!("YOURIP {}\n", peer_ip);
@@ -83,6 +143,38 @@ async fn handle_inner(stream: &mut TcpStream, buf: &str, rand_pool: &[u8]) -> Re
             println!("Response: {:?}", resp);
             Ok(HandleState::Next)
         }
+        _ if buf.starts_with("SMALL") => {
+            // Small object of responsiveness probes, sent in one write to dodge Nagle.
+            let mut resp = rand_pool[..SMALL_SIZE - 2].to_vec();
+            resp.extend_from_slice(b"\r\n");
+            stream.write_all(&resp).await?;
+            metrics::TCP.sent(resp.len());
+            Ok(HandleState::Next)
+        }
+        _ if buf.starts_with("CPU") => {
+            let report = last_report
+                .as_ref()
+                .ok_or(anyhow!("No download to report CPU utilisation of"))?;
+            let resp = format!("{}\n", report);
+            stream.write_all(resp.as_bytes()).await?;
+            println!("Response: {:?}", resp);
+            Ok(HandleState::Next)
+        }
+        _ if buf.starts_with("CONGESTION ") => {
+            let name = buf[11..].trim();
+            nettest::set_congestion(&*stream, name)
+                .with_context(|| format!("Failed to use congestion control {}", name))?;
+            let resp = format!("OK {}\n", name);
+            stream.write_all(resp.as_bytes()).await?;
+            println!("Response: {:?}", resp);
+            Ok(HandleState::Next)
+        }
+        _ if buf.starts_with("MARKING") => {
+            let resp = format!("MARKING {}\n", received_tos(stream)?);
+            stream.write_all(resp.as_bytes()).await?;
+            println!("Response: {:?}", resp);
+            Ok(HandleState::Next)
+        }
         _ if buf.starts_with("HI") => {
             const RESP: &str = "HELLO 2.7(compatiable server)\n";
             stream.write_all(RESP.as_bytes()).await?;
@@ -99,10 +191,80 @@ async fn handle_inner(stream: &mut TcpStream, buf: &str, rand_pool: &[u8]) -> Re
     }
 }
 
+/// TOS byte the client's handshake arrived with, from `IP_PKTOPTIONS`.
+/// Linux only keeps it for IPv4.
+#[cfg(target_os = "linux")]
+fn received_tos(stream: &TcpStream) -> Result<u8> {
+    use std::mem::size_of_val;
+    use std::os::unix::io::AsRawFd;
+    // Not in libc, from linux/in.h.
+    const IP_PKTOPTIONS: libc::c_int = 9;
+    if !stream.peer_addr()?.is_ipv4() {
+        return Err(anyhow!("TOS of received packets is only known for IPv4"));
+    }
+    let fd = stream.as_raw_fd();
+    let on: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_IP,
+            libc::IP_RECVTOS,
+            &on as *const libc::c_int as *const libc::c_void,
+            size_of_val(&on) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(anyhow!(
+            "Failed to set IP_RECVTOS: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    // u64 keeps control messages aligned.
+    let mut control = [0u64; 8];
+    let mut len = size_of_val(&control) as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::IPPROTO_IP,
+            IP_PKTOPTIONS,
+            control.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(anyhow!(
+            "Failed to get IP_PKTOPTIONS: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    // The options are control messages, walk them like the ones of recvmsg.
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = len as _;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::IPPROTO_IP && (*cmsg).cmsg_type == libc::IP_TOS {
+                // An int here, unlike the byte of recvmsg.
+                let tos = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                return Ok(tos as u8);
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Err(anyhow!("Kernel didn't report TOS of received packets"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn received_tos(_stream: &TcpStream) -> Result<u8> {
+    Err(anyhow!("TOS of received packets isn't supported"))
+}
+
 async fn handle_download(
     stream: &mut TcpStream,
     buf: &str,
     rand_pool: &[u8],
+    zerocopy: Option<&Arc<RandPool>>,
 ) -> Result<HandleState> {
     let download_bytes: usize = buf[8..].trim().parse()?;
     const START: &[u8] = b"DOWNLOAD ";
@@ -115,18 +277,105 @@ async fn handle_download(
     } else {
         stream.write_all(START).await?;
         let mut size = download_bytes - 11;
-        let mut rng = Xoshiro256Plus::from_entropy();
-        while size > 0 {
-            let start = rng.gen_range(0, MB);
-            let len = size.min(MB);
-            stream.write_all(&rand_pool[start..start + len]).await?;
-            size -= len;
+        if let Some(pool) = zerocopy {
+            stream.flush().await?;
+            let pool = Arc::clone(pool);
+            let socket = stream.clone();
+            async_std::task::spawn_blocking(move || send_all(&pool, &socket, size)).await?;
+        } else {
+            let mut rng = Xoshiro256Plus::from_entropy();
+            while size > 0 {
+                let start = rng.gen_range(0, MB);
+                let len = size.min(MB);
+                stream.write_all(&rand_pool[start..start + len]).await?;
+                size -= len;
+            }
         }
         stream.write_all(END).await?;
     }
+    metrics::TCP.sent(download_bytes.max(2));
     Ok(HandleState::Next)
 }
 
+/// Send `size` bytes of `pool` to the nonblocking `socket`,
+/// waiting for it to become writable when its buffer is full.
+fn send_all(pool: &RandPool, socket: &TcpStream, mut size: usize) -> std::io::Result<()> {
+    use std::io::ErrorKind;
+    use std::os::unix::io::AsRawFd;
+    let mut offset = 0;
+    while size > 0 {
+        match pool.send(socket, offset, size) {
+            Ok(len) => {
+                size -= len;
+                offset = (offset + len) % pool.len();
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                let mut pollfd = libc::pollfd {
+                    fd: socket.as_raw_fd(),
+                    events: libc::POLLOUT,
+                    revents: 0,
+                };
+                if unsafe { libc::poll(&mut pollfd, 1, -1) } < 0 {
+                    let e = std::io::Error::last_os_error();
+                    if e.kind() != ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Send bulk data frames until the client quits or closes the connection,
+/// with the small object of every SMALL it sends meanwhile in a frame of its own.
+async fn handle_load(stream: &mut TcpStream, rand_pool: &[u8]) -> Result<HandleState> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let small = Arc::new(AtomicUsize::new(0));
+    let requests = {
+        let (stop, small) = (Arc::clone(&stop), Arc::clone(&small));
+        let mut reader = BufReader::new(stream.clone());
+        async_std::task::spawn(async move {
+            let mut line = String::new();
+            loop {
+                line.clear();
+                match reader.read_line(&mut line).await {
+                    Ok(len) if len > 0 && line.starts_with("SMALL") => {
+                        metrics::TCP.received(len);
+                        small.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Ok(len) if len > 0 && !line.starts_with("QUIT") => {
+                        metrics::TCP.received(len);
+                    }
+                    _ => break,
+                }
+            }
+            stop.store(true, Ordering::Relaxed);
+        })
+    };
+    let mut rng = Xoshiro256Plus::from_entropy();
+    while !stop.load(Ordering::Relaxed) {
+        let mut frames = Vec::with_capacity(LOAD_FRAME + 2 * wire::FRAME_HEADER_LEN);
+        for _ in 0..small.swap(0, Ordering::Relaxed) {
+            frames.extend_from_slice(&wire::frame_header(LOAD_SMALL, SMALL_SIZE));
+            frames.extend_from_slice(&rand_pool[..SMALL_SIZE]);
+        }
+        let start = rng.gen_range(0, MB);
+        frames.extend_from_slice(&wire::frame_header(LOAD_DATA, LOAD_FRAME));
+        frames.extend_from_slice(&rand_pool[start..start + LOAD_FRAME]);
+        // The client ends the load by closing the connection, so does a failed write.
+        if stream.write_all(&frames).await.is_err() {
+            break;
+        }
+        metrics::TCP.sent(frames.len());
+    }
+    // Stops once the client closes the connection, after a failed write too.
+    requests.await;
+    Ok(HandleState::Quit)
+}
+
 async fn handle_upload(mut stream: &mut TcpStream, buf: &str) -> Result<HandleState> {
     let upload_bytes = buf
         .split_whitespace()
@@ -134,22 +383,28 @@ async fn handle_upload(mut stream: &mut TcpStream, buf: &str) -> Result<HandleSt
         .ok_or(anyhow!("Upload command don't have bytes value"))?
         .parse::<usize>()?
         + 1;
+    let cpu = CpuTime::now();
     let count = Count {
         reader: &mut stream,
         buffer: &mut vec![0u8; upload_bytes.min(MB)],
         count: 0,
     };
     let size = count.await? + buf.as_bytes().len();
+    metrics::TCP.received(size - buf.len());
+    let cpu = cpu.usage();
     let time_stamp = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap()
         .as_secs();
     if upload_bytes > size {
         // FIXME: unknown data loss
-        eprintln!("\x1B[31mDiff in req and res: {}\x1B[39m", upload_bytes - size);
+        eprintln!(
+            "\x1B[31mDiff in req and res: {}\x1B[39m",
+            upload_bytes - size
+        );
     }
-    let ans = format!("Ok {} {}\n", size - 1, time_stamp);
-    stream.write_all(dbg!(ans).as_bytes()).await?;
+    let ans = format!("Ok {} {} {}\n", size - 1, time_stamp, cpu.reply());
+    stream.write_all(ans.as_bytes()).await?;
     Ok(HandleState::Next)
 }
 
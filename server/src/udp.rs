@@ -0,0 +1,355 @@
+use super::metrics;
+use anyhow::{anyhow, Result};
+use nettest::wire::{self, DATA, ECHO, FIN, MARK, REPORT, REPORT_LEN};
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// Time between reports sent while a load test runs.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// Missing packets further behind than this are counted as lost for good.
+const REORDER_WINDOW: u64 = 4096;
+/// Sessions idle for this long are dropped.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+struct Report {
+    packets: u64,
+    bytes: u64,
+    lost: u64,
+    duplicated: u64,
+    reordered: u64,
+    /// Microseconds.
+    jitter: u64,
+    /// Microseconds.
+    time: u64,
+}
+
+impl Report {
+    fn encode(&self, last: bool) -> Vec<u8> {
+        let mut buf = wire::packet(REPORT, &[]);
+        buf.push(last as u8);
+        for v in &[
+            self.packets,
+            self.bytes,
+            self.lost,
+            self.duplicated,
+            self.reordered,
+            self.jitter,
+            self.time,
+        ] {
+            buf.extend_from_slice(&v.to_be_bytes());
+        }
+        debug_assert_eq!(buf.len(), REPORT_LEN);
+        buf
+    }
+}
+
+/// Receiver side of one `udpload` client.
+struct Session {
+    start: Instant,
+    last_seen: Instant,
+    next_seq: u64,
+    /// Sequence numbers skipped that may still arrive late.
+    missing: BTreeSet<u64>,
+    /// Packets given up on, out of `missing`.
+    lost: u64,
+    /// Total since the start of the test.
+    total: Report,
+    /// Since the last report.
+    interval: Report,
+    interval_start: Instant,
+    /// Last relative transit time in microseconds, RFC 3550 jitter state.
+    transit: Option<i64>,
+    jitter: f64,
+}
+
+impl Session {
+    fn new() -> Session {
+        let now = Instant::now();
+        Session {
+            start: now,
+            last_seen: now,
+            next_seq: 0,
+            missing: BTreeSet::new(),
+            lost: 0,
+            total: Report::default(),
+            interval: Report::default(),
+            interval_start: now,
+            transit: None,
+            jitter: 0.0,
+        }
+    }
+
+    fn lost(&self) -> u64 {
+        self.lost + self.missing.len() as u64
+    }
+
+    fn data(&mut self, seq: u64, sent: u64, len: usize) {
+        let now = Instant::now();
+        self.last_seen = now;
+        let lost = self.lost();
+        if seq >= self.next_seq {
+            if seq - self.next_seq > REORDER_WINDOW {
+                self.lost += seq - self.next_seq;
+            } else {
+                self.missing.extend(self.next_seq..seq);
+            }
+            self.next_seq = seq + 1;
+            while let Some(&first) = self.missing.iter().next() {
+                if first + REORDER_WINDOW >= self.next_seq {
+                    break;
+                }
+                self.missing.remove(&first);
+                self.lost += 1;
+            }
+        } else if self.missing.remove(&seq) {
+            self.interval.reordered += 1;
+            self.total.reordered += 1;
+        } else {
+            self.interval.duplicated += 1;
+            self.total.duplicated += 1;
+            return;
+        }
+        // A reordered packet makes up for one counted lost before.
+        let lost_now = self.lost();
+        self.interval.lost = (self.interval.lost + lost_now).saturating_sub(lost);
+        self.total.lost = lost_now;
+        for report in &mut [&mut self.interval, &mut self.total] {
+            report.packets += 1;
+            report.bytes += len as u64;
+        }
+        let transit = (now - self.start).as_micros() as i64 - sent as i64;
+        if let Some(last) = self.transit {
+            let d = (transit - last).abs() as f64;
+            self.jitter += (d - self.jitter) / 16.0;
+        }
+        self.transit = Some(transit);
+    }
+
+    /// Report of the interval if it's over.
+    fn report(&mut self) -> Option<Vec<u8>> {
+        if self.interval_start.elapsed() < REPORT_INTERVAL {
+            return None;
+        }
+        self.interval.jitter = self.jitter as u64;
+        self.interval.time = self.interval_start.elapsed().as_micros() as u64;
+        let report = self.interval.encode(false);
+        self.interval = Report::default();
+        self.interval_start = Instant::now();
+        Some(report)
+    }
+
+    fn finish(&mut self, sent: u64) -> Vec<u8> {
+        // Everything not received by now is lost, including the tail.
+        self.total.lost = sent.saturating_sub(self.total.packets);
+        self.total.jitter = self.jitter as u64;
+        self.total.time = self.start.elapsed().as_micros() as u64;
+        self.total.encode(true)
+    }
+}
+
+/// Receiver of nettest UDP tests, fed with packets by whoever owns the socket.
+#[derive(Default)]
+pub struct Server {
+    /// Keyed by client address and test ID, every run of a client is a new test.
+    sessions: HashMap<(SocketAddr, u64), Session>,
+}
+
+impl Server {
+    /// `tos` is the TOS byte or traffic class the packet arrived with, if known.
+    pub fn handle(
+        &mut self,
+        socket: &UdpSocket,
+        pkt: &[u8],
+        from: SocketAddr,
+        tos: Option<u8>,
+    ) -> Result<()> {
+        metrics::UDP.received(pkt.len());
+        let ty = wire::kind(pkt).ok_or(anyhow!("Packet is too short"))?;
+        let u64_at =
+            |i: usize| wire::u64_at(pkt, wire::field(i)).ok_or(anyhow!("Packet is too short"));
+        match ty {
+            DATA => {
+                let (id, seq, sent) = (u64_at(0)?, u64_at(1)?, u64_at(2)?);
+                let session = self.sessions.entry((from, id)).or_insert_with(|| {
+                    println!("UDP load test from {}", from);
+                    metrics::UDP.connect();
+                    Session::new()
+                });
+                session.data(seq, sent, pkt.len());
+                if let Some(report) = session.report() {
+                    metrics::UDP.sent(socket.send_to(&report, from)?);
+                }
+            }
+            FIN => {
+                let (id, sent) = (u64_at(0)?, u64_at(1)?);
+                // Retransmitted FINs get the same answer.
+                let session = self.sessions.entry((from, id)).or_insert_with(|| {
+                    metrics::UDP.connect();
+                    Session::new()
+                });
+                let report = session.finish(sent);
+                metrics::UDP.sent(socket.send_to(&report, from)?);
+                println!(
+                    "UDP load test from {} finished: {} of {} packets received",
+                    from, session.total.packets, sent
+                );
+            }
+            ECHO => {
+                metrics::UDP.sent(socket.send_to(pkt, from)?);
+            }
+            MARK => {
+                let mut reply = pkt.to_vec();
+                reply.push(tos.is_some() as u8);
+                reply.push(tos.unwrap_or(0));
+                metrics::UDP.sent(socket.send_to(&reply, from)?);
+            }
+            _ => return Err(anyhow!("Unknown UDP packet type {}", ty)),
+        }
+        self.sessions.retain(|_, s| {
+            let alive = s.last_seen.elapsed() < SESSION_TIMEOUT;
+            if !alive {
+                metrics::UDP.close();
+            }
+            alive
+        });
+        Ok(())
+    }
+}
+
+/// UDP server for when QUIC is disabled and nothing else owns the port.
+pub fn server(addr: SocketAddr) -> Result<()> {
+    let socket = UdpSocket::bind(addr)?;
+    set_recv_tos(&socket)?;
+    println!("UDP server listening on {}", addr);
+    let mut server = Server::default();
+    let mut buf = vec![0u8; 65535];
+    loop {
+        let (len, from, tos) = recv_from(&socket, &mut buf)?;
+        let pkt = &buf[..len];
+        if !wire::is_nettest(pkt) {
+            continue;
+        }
+        if let Err(e) = server.handle(&socket, pkt, from, tos) {
+            metrics::UDP.error();
+            eprintln!("UDP packet from {} error: {}", from, e);
+        }
+    }
+}
+
+/// Ask for the TOS byte or traffic class of received packets, see `recv_from`.
+#[cfg(target_os = "linux")]
+pub fn set_recv_tos(socket: &UdpSocket) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let (level, name) = if socket.local_addr()?.is_ipv4() {
+        (libc::IPPROTO_IP, libc::IP_RECVTOS)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS)
+    };
+    let on: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &on as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of_val(&on) as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Failed to receive TOS of packets: {}",
+            io::Error::last_os_error()
+        ))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_recv_tos(_socket: &UdpSocket) -> Result<()> {
+    Ok(())
+}
+
+/// `UdpSocket::recv_from` that also returns the TOS byte or traffic class
+/// the packet arrived with, once `set_recv_tos` is called.
+#[cfg(target_os = "linux")]
+pub fn recv_from(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<u8>)> {
+    use std::mem::{size_of_val, zeroed};
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV6};
+    use std::os::unix::io::AsRawFd;
+    let mut name: libc::sockaddr_storage = unsafe { zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // u64 keeps control messages aligned, there is only one of an int.
+    let mut control = [0u64; 8];
+    let mut msg: libc::msghdr = unsafe { zeroed() };
+    msg.msg_name = &mut name as *mut libc::sockaddr_storage as *mut libc::c_void;
+    msg.msg_namelen = size_of_val(&name) as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = size_of_val(&control) as _;
+    let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut tos = None;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let data = libc::CMSG_DATA(cmsg);
+            match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                // IP_TOS is one byte, IPV6_TCLASS an int.
+                (libc::IPPROTO_IP, libc::IP_TOS) => tos = Some(*data),
+                (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                    tos = Some(std::ptr::read_unaligned(data as *const libc::c_int) as u8)
+                }
+                _ => {}
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    let from = match name.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(&name as *const _ as *const libc::sockaddr_in) };
+            SocketAddr::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)).into(),
+                u16::from_be(addr.sin_port),
+            )
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(&name as *const _ as *const libc::sockaddr_in6) };
+            SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )
+            .into()
+        }
+        family => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Unknown address family {}", family),
+            ))
+        }
+    };
+    Ok((len as usize, from, tos))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn recv_from(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<u8>)> {
+    let (len, from) = socket.recv_from(buf)?;
+    Ok((len, from, None))
+}
@@ -0,0 +1,334 @@
+use crate::config::{Pattern, TestConfig};
+use crate::error::{Context, Error, Result};
+use crate::ping::{self, Icmphdr};
+use crate::socket::{self, TcpOptions};
+use crate::{Measurement, Probe, Task};
+use async_std::future;
+use async_std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use log::info;
+use std::future::Future;
+use std::mem::size_of;
+use std::net;
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Future returned by `AsyncTask::run`.
+pub type TaskFuture<'a> = Pin<Box<dyn Future<Output = Result<Measurement>> + Send + 'a>>;
+
+/// A `Task` that waits on sockets without blocking a thread, so many of them
+/// can run concurrently on one async-std executor.
+pub trait AsyncTask: Send {
+    fn run(&mut self) -> TaskFuture<'_>;
+    /// Log statistics only the task itself keeps track of, after all runs.
+    fn summary(&self) {}
+}
+
+impl<T: AsyncTask + ?Sized> AsyncTask for Box<T> {
+    fn run(&mut self) -> TaskFuture<'_> {
+        (**self).run()
+    }
+
+    fn summary(&self) {
+        (**self).summary()
+    }
+}
+
+/// Runs an `AsyncTask` to completion on the current thread, for use as a `Task`.
+pub struct Blocking<T>(pub T);
+
+impl<T: AsyncTask> Task for Blocking<T> {
+    fn run(&mut self) -> Result<Measurement> {
+        async_std::task::block_on(self.0.run())
+    }
+
+    fn summary(&self) {
+        self.0.summary()
+    }
+}
+
+/// Runs a blocking `Task` on async-std's thread pool for blocking work,
+/// for tasks that don't have a native async implementation yet.
+///
+/// A run starts when `run` is called rather than when the future is first
+/// polled, so runs of several tasks overlap even if they are awaited one after
+/// another. Dropping the future doesn't stop the run, the next one waits for it.
+pub struct Threaded<T>(Arc<Mutex<T>>);
+
+impl<T> Threaded<T> {
+    pub fn new(task: T) -> Threaded<T> {
+        Threaded(Arc::new(Mutex::new(task)))
+    }
+}
+
+impl<T: Task + Send + 'static> AsyncTask for Threaded<T> {
+    fn run(&mut self) -> TaskFuture<'_> {
+        let task = Arc::clone(&self.0);
+        // A run that panicked was reported by its future, the next one carries on.
+        Box::pin(async_std::task::spawn_blocking(move || {
+            task.lock().unwrap_or_else(PoisonError::into_inner).run()
+        }))
+    }
+
+    fn summary(&self) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .summary()
+    }
+}
+
+async fn resolve(addr: &str, config: &TestConfig) -> Result<SocketAddr> {
+    let family = config.resolve_family();
+    addr.to_socket_addrs()
+        .await
+        .map_err(|e| Error::Resolve(format!("Can't resolve IP address of {}: {}", addr, e)))?
+        .find(|a| family.matches(a))
+        .ok_or_else(|| Error::Resolve(format!("Don't have {:?} address of {}", family, addr)))
+}
+
+pub(crate) async fn timeout<T, F>(dur: Duration, f: F) -> Result<T>
+where
+    F: Future<Output = std::io::Result<T>>,
+{
+    match future::timeout(dur, f).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(Error::Timeout),
+    }
+}
+
+/// Latency of ICMP echo, see `PingTask`.
+pub struct AsyncPingTask {
+    target: SocketAddr,
+    socket: UdpSocket,
+    timeout: Duration,
+    echo_hdr: Icmphdr,
+    size: usize,
+    pattern: Pattern,
+}
+
+impl AsyncPingTask {
+    /// `addr` is an IP address or a hostname.
+    pub async fn new(addr: &str, config: &TestConfig) -> Result<AsyncPingTask> {
+        let target = match addr.parse() {
+            Ok(ip) => SocketAddr::new(ip, 0),
+            Err(_) => resolve(&format!("{}:0", addr), config).await?,
+        };
+        let socket = ping::icmp_socket(&target, config)?;
+        // Datagram ICMP sockets send and receive like UDP ones.
+        let socket = unsafe { net::UdpSocket::from_raw_fd(socket.into_raw_fd()) };
+        Ok(AsyncPingTask {
+            target,
+            socket: UdpSocket::from(socket),
+            timeout: config.timeout,
+            echo_hdr: Icmphdr::echo(0, target.is_ipv6()),
+            size: config.size.max(size_of::<Icmphdr>()),
+            pattern: config.pattern,
+        })
+    }
+}
+
+impl AsyncTask for AsyncPingTask {
+    fn run(&mut self) -> TaskFuture<'_> {
+        Box::pin(async move {
+            self.echo_hdr.bump();
+            let mut buffer = self.echo_hdr.vec();
+            buffer.resize(self.size, 0);
+            self.pattern.fill(&mut buffer[size_of::<Icmphdr>()..]);
+            let start = Instant::now();
+            let sent = timeout(self.timeout, self.socket.send_to(&buffer, self.target))
+                .await
+                .context("Failed to send echo request")?;
+            if sent != buffer.len() {
+                return Err(Error::Protocol(format!(
+                    "Failed to send echo request({} bytes), only {} bytes sent",
+                    buffer.len(),
+                    sent
+                )));
+            }
+            // Skip late replies of earlier probes.
+            let (size, reply) = loop {
+                let remain = self
+                    .timeout
+                    .checked_sub(start.elapsed())
+                    .ok_or(Error::Timeout)?;
+                let (len, from) = timeout(remain, self.socket.recv_from(&mut buffer))
+                    .await
+                    .context("Failed to receive echo reply")?;
+                let reply = Icmphdr::reply(&buffer[..len], from.ip(), self.target.ip())?;
+                if reply.seq == self.echo_hdr.seq {
+                    break (len, reply);
+                }
+            };
+            let time = start.elapsed();
+            info!(
+                "{} bytes from {}: icmp_seq={} time={:?}",
+                size,
+                self.target.ip(),
+                reply.seq,
+                time
+            );
+            Ok(Measurement::Time(Probe::new(
+                self.target,
+                reply.seq as u64,
+                size,
+                time,
+            )))
+        })
+    }
+}
+
+/// Latency of TCP handshakes, see `TcpingTask`.
+pub struct AsyncTcpingTask {
+    target: SocketAddr,
+    config: TestConfig,
+    options: TcpOptions,
+    seq: u16,
+}
+
+impl AsyncTcpingTask {
+    pub async fn new(
+        addr: &str,
+        config: &TestConfig,
+        options: &TcpOptions,
+    ) -> Result<AsyncTcpingTask> {
+        let target = resolve(addr, config).await?;
+        info!("Ping to {} using TCP", target);
+        Ok(AsyncTcpingTask {
+            target,
+            config: config.clone(),
+            options: options.clone(),
+            seq: 0,
+        })
+    }
+}
+
+impl AsyncTask for AsyncTcpingTask {
+    fn run(&mut self) -> TaskFuture<'_> {
+        Box::pin(async move {
+            self.seq = self.seq.wrapping_add(1);
+            let start = Instant::now();
+            let tcp = if self.config.binds() || self.options != TcpOptions::default() {
+                // async-std can't connect a socket with options set before,
+                // connect it on the thread pool for blocking work instead.
+                let (target, config) = (self.target, self.config.clone());
+                let options = self.options.clone();
+                let tcp = async_std::task::spawn_blocking(move || {
+                    socket::tcp_connect(&target, &config, &options)
+                })
+                .await?;
+                TcpStream::from(tcp)
+            } else {
+                timeout(self.config.timeout, TcpStream::connect(self.target)).await?
+            };
+            let time = start.elapsed();
+            tcp.shutdown(net::Shutdown::Both)?;
+            info!(
+                "Connected to {}: seq={} time={:?}",
+                self.target, self.seq, time
+            );
+            Ok(Measurement::Time(Probe::new(
+                self.target,
+                self.seq as u64,
+                0,
+                time,
+            )))
+        })
+    }
+}
+
+/// Latency of UDP echo, see `UdpingTask`.
+pub struct AsyncUdpingTask {
+    target: SocketAddr,
+    socket: UdpSocket,
+    timeout: Duration,
+    seq: u16,
+    size: usize,
+    pattern: Pattern,
+}
+
+impl AsyncUdpingTask {
+    pub async fn new(addr: &str, config: &TestConfig) -> Result<AsyncUdpingTask> {
+        let target = resolve(addr, config).await?;
+        // Connecting a UDP socket doesn't wait for anything.
+        let socket = UdpSocket::from(socket::udp_connect(&target, config)?);
+        info!("Ping to {} using UDP", target);
+        Ok(AsyncUdpingTask {
+            target,
+            socket,
+            timeout: config.timeout,
+            seq: 0,
+            size: config.size.max(2),
+            pattern: config.pattern,
+        })
+    }
+}
+
+impl AsyncTask for AsyncUdpingTask {
+    fn run(&mut self) -> TaskFuture<'_> {
+        Box::pin(async move {
+            self.seq = self.seq.wrapping_add(1);
+            let mut buffer = vec![0u8; self.size];
+            buffer[..2].copy_from_slice(&self.seq.to_le_bytes());
+            self.pattern.fill(&mut buffer[2..]);
+            let start = Instant::now();
+            let sent = timeout(self.timeout, self.socket.send(&buffer)).await?;
+            // Skip late replies of earlier probes.
+            let received = loop {
+                let remain = self
+                    .timeout
+                    .checked_sub(start.elapsed())
+                    .ok_or(Error::Timeout)?;
+                let len = timeout(remain, self.socket.recv(&mut buffer)).await?;
+                if len >= 2 && buffer[..2] == self.seq.to_le_bytes() {
+                    break len;
+                }
+            };
+            let time = start.elapsed();
+            if received != sent {
+                return Err(Error::UnexpectedReply(format!(
+                    "Send {} bytes but receive {} bytes",
+                    sent, received
+                )));
+            }
+            info!(
+                "{} bytes from {}: seq={} time={:?}",
+                received, self.target, self.seq, time
+            );
+            Ok(Measurement::Time(Probe::new(
+                self.target,
+                self.seq as u64,
+                received,
+                time,
+            )))
+        })
+    }
+}
+
+#[test]
+fn adapters() {
+    /// Task counting its runs, as the seq of its probes.
+    struct Counter(u64);
+
+    impl Task for Counter {
+        fn run(&mut self) -> Result<Measurement> {
+            self.0 += 1;
+            let target = "127.0.0.1:8080".parse().unwrap();
+            let time = Duration::from_millis(1);
+            Ok(Measurement::Time(Probe::new(target, self.0, 0, time)))
+        }
+    }
+
+    let mut task = Blocking(Threaded::new(Counter(0)));
+    for seq in 1..=3 {
+        match task.run().unwrap() {
+            Measurement::Time(probe) => assert_eq!(probe.seq, seq),
+            m => panic!("Unexpected measurement {:?}", m),
+        }
+    }
+    // A dropped run leaves the task usable.
+    let mut threaded = Threaded::new(Counter(0));
+    drop(threaded.run());
+    assert!(async_std::task::block_on(threaded.run()).is_ok());
+}
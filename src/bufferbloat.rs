@@ -0,0 +1,180 @@
+use crate::asynctask::{AsyncTask, Threaded};
+use crate::error::{Error, Result};
+use crate::stats::percentile;
+use crate::{Measurement, Probe, Task};
+use async_std::future;
+use log::info;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Number of probes sent before the load starts.
+const IDLE_PROBES: usize = 20;
+
+/// Latency under load: probe latency while idle, then while a bandwidth task saturates the link.
+pub struct BufferbloatTask {
+    probe: Box<dyn AsyncTask>,
+    load: Threaded<Box<dyn Task + Send>>,
+    interval: Duration,
+    idle: Vec<Duration>,
+    loaded: Vec<Duration>,
+    /// Probes timed out or failed while loaded.
+    loaded_lost: usize,
+    runs: u64,
+}
+
+impl BufferbloatTask {
+    /// Send a `probe` every `interval`, `load` is run once per test.
+    pub fn new(
+        probe: Box<dyn AsyncTask>,
+        load: Box<dyn Task + Send>,
+        interval: Duration,
+    ) -> BufferbloatTask {
+        info!(
+            "Latency under load test, probe every {} ms",
+            interval.as_millis()
+        );
+        BufferbloatTask {
+            probe,
+            load: Threaded::new(load),
+            interval,
+            idle: Vec::new(),
+            loaded: Vec::new(),
+            loaded_lost: 0,
+            runs: 0,
+        }
+    }
+
+    async fn run_async(&mut self) -> Result<Measurement> {
+        let started = SystemTime::now();
+        let mut idle = Vec::new();
+        let mut error = None;
+        for _ in 0..IDLE_PROBES {
+            let start = Instant::now();
+            match run_probe(&mut *self.probe).await {
+                Ok(probe) => idle.push(probe),
+                Err(e) => error = Some(e),
+            }
+            if let Some(wait) = self.interval.checked_sub(start.elapsed()) {
+                async_std::task::sleep(wait).await;
+            }
+        }
+        let target = match (idle.first(), error) {
+            (Some(probe), _) => probe.target,
+            (None, Some(e)) => return Err(e.context("All idle probes failed")),
+            (None, None) => unreachable!("At least one idle probe is sent"),
+        };
+        let mut idle: Vec<Duration> = idle.iter().map(|p| p.time).collect();
+        // The load runs on the thread pool for blocking work while probes are sent.
+        let mut load = self.load.run();
+        let mut loaded = Vec::new();
+        let mut lost = 0;
+        let mut error = None;
+        let result = loop {
+            let start = Instant::now();
+            match run_probe(&mut *self.probe).await {
+                Ok(probe) => loaded.push(probe.time),
+                Err(e) => {
+                    lost += 1;
+                    error = Some(e);
+                }
+            }
+            // Wait for the next probe or the end of the load, whichever comes first.
+            let wait = self.interval.saturating_sub(start.elapsed());
+            if let Ok(result) = future::timeout(wait, &mut load).await {
+                break result;
+            }
+        };
+        result?;
+        if loaded.is_empty() {
+            let e = error.unwrap_or_else(|| {
+                Error::InvalidInput("Load finished before the first probe".into())
+            });
+            return Err(e.context("All probes under load failed"));
+        }
+        log_percentiles("Idle", &mut idle);
+        log_percentiles("Loaded", &mut loaded);
+        let increase = percentile(&loaded, 50.0).saturating_sub(percentile(&idle, 50.0));
+        info!(
+            "Latency increase: {:.3} ms, {} probes lost under load, grade {}",
+            ms(increase),
+            lost,
+            grade(increase)
+        );
+        self.idle.extend(idle);
+        self.loaded.extend(loaded);
+        self.loaded_lost += lost;
+        self.runs += 1;
+        Ok(Measurement::Time(Probe {
+            target,
+            seq: self.runs,
+            bytes: 0,
+            sent: started,
+            time: increase,
+        }))
+    }
+}
+
+async fn run_probe(task: &mut dyn AsyncTask) -> Result<Probe> {
+    match task.run().await {
+        Ok(Measurement::Time(probe)) => Ok(probe),
+        Ok(Measurement::Speed(..)) => Err(Error::InvalidInput(
+            "Probe task doesn't measure latency".into(),
+        )),
+        Err(e) => {
+            info!("{}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Grade of the latency added by load, the thresholds are those of the Waveform bufferbloat test.
+fn grade(increase: Duration) -> &'static str {
+    match increase.as_millis() {
+        0..=4 => "A+",
+        5..=29 => "A",
+        30..=59 => "B",
+        60..=199 => "C",
+        200..=399 => "D",
+        _ => "F",
+    }
+}
+
+fn ms(t: Duration) -> f64 {
+    t.as_micros() as f64 / 1000.0
+}
+
+fn log_percentiles(name: &str, times: &mut [Duration]) {
+    times.sort();
+    info!(
+        "{} latency p25/p50/p75/p90/p99 = {:.3}/{:.3}/{:.3}/{:.3}/{:.3} ms ({} probes)",
+        name,
+        ms(percentile(times, 25.0)),
+        ms(percentile(times, 50.0)),
+        ms(percentile(times, 75.0)),
+        ms(percentile(times, 90.0)),
+        ms(percentile(times, 99.0)),
+        times.len()
+    );
+}
+
+impl Task for BufferbloatTask {
+    fn run(&mut self) -> Result<Measurement> {
+        async_std::task::block_on(self.run_async())
+    }
+
+    fn summary(&self) {
+        if self.idle.is_empty() || self.loaded.is_empty() {
+            return;
+        }
+        let mut idle = self.idle.clone();
+        let mut loaded = self.loaded.clone();
+        log_percentiles("Idle", &mut idle);
+        log_percentiles("Loaded", &mut loaded);
+        let increase = percentile(&loaded, 50.0).saturating_sub(percentile(&idle, 50.0));
+        info!(
+            "Latency increase: {:.3} ms, {:.2}% probes lost under load, grade {}",
+            ms(increase),
+            100.0 * self.loaded_lost as f64 / (self.loaded.len() + self.loaded_lost) as f64,
+            grade(increase)
+        );
+    }
+}
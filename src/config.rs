@@ -0,0 +1,264 @@
+use crate::error::Error;
+use rand::{Rng, SeedableRng};
+use rand_xoshiro::Xoshiro256Plus;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Address family the target is resolved to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Family {
+    /// Whatever the resolver returns first.
+    Any,
+    V4,
+    V6,
+}
+
+impl Family {
+    pub fn matches(self, addr: &SocketAddr) -> bool {
+        match self {
+            Family::Any => true,
+            Family::V4 => addr.is_ipv4(),
+            Family::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+/// Content of probe payloads and uploaded data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pattern {
+    /// Random printable ASCII, so line based protocols never see a line break.
+    Random,
+    /// Every byte is the same.
+    Byte(u8),
+}
+
+impl Pattern {
+    pub fn fill(self, buf: &mut [u8]) {
+        match self {
+            Pattern::Random => {
+                let mut rng = Xoshiro256Plus::from_entropy();
+                for b in buf {
+                    *b = rng.gen_range(0x20, 0x7F);
+                }
+            }
+            Pattern::Byte(byte) => {
+                for b in buf {
+                    *b = byte;
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = Error;
+
+    /// `random`, `zero` or a byte in decimal or `0x` hex.
+    fn from_str(s: &str) -> Result<Pattern, Error> {
+        let byte = match s {
+            "random" => return Ok(Pattern::Random),
+            "zero" => Ok(0),
+            _ if s.starts_with("0x") => u8::from_str_radix(&s[2..], 16),
+            _ => s.parse(),
+        };
+        byte.map(Pattern::Byte)
+            .map_err(|_| Error::InvalidInput(format!("Invalid payload pattern {}", s)))
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pattern::Random => write!(f, "random"),
+            Pattern::Byte(b) => write!(f, "{:#04x}", b),
+        }
+    }
+}
+
+/// ECN codepoint, the low 2 bits of the TOS byte or traffic class.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ecn {
+    NotEct,
+    Ect1,
+    Ect0,
+    /// Congestion Experienced, set by routers on ECN capable packets instead of dropping them.
+    Ce,
+}
+
+impl Ecn {
+    pub fn bits(self) -> u8 {
+        match self {
+            Ecn::NotEct => 0,
+            Ecn::Ect1 => 1,
+            Ecn::Ect0 => 2,
+            Ecn::Ce => 3,
+        }
+    }
+
+    /// ECN field of a TOS byte.
+    pub fn from_tos(tos: u8) -> Ecn {
+        match tos & 3 {
+            0 => Ecn::NotEct,
+            1 => Ecn::Ect1,
+            2 => Ecn::Ect0,
+            _ => Ecn::Ce,
+        }
+    }
+}
+
+impl FromStr for Ecn {
+    type Err = Error;
+
+    /// `not-ect`, `ect0`, `ect1`, `ce` or the codepoint 0 to 3.
+    fn from_str(s: &str) -> Result<Ecn, Error> {
+        match s.to_ascii_lowercase().as_str() {
+            "not-ect" | "0" => Ok(Ecn::NotEct),
+            "ect1" | "ect(1)" | "1" => Ok(Ecn::Ect1),
+            "ect0" | "ect(0)" | "2" => Ok(Ecn::Ect0),
+            "ce" | "3" => Ok(Ecn::Ce),
+            _ => Err(Error::InvalidInput(format!("Invalid ECN codepoint {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for Ecn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ecn::NotEct => write!(f, "Not-ECT"),
+            Ecn::Ect1 => write!(f, "ECT(1)"),
+            Ecn::Ect0 => write!(f, "ECT(0)"),
+            Ecn::Ce => write!(f, "CE"),
+        }
+    }
+}
+
+/// Options shared by every task, built like
+/// `TestConfig::new().timeout(Duration::from_millis(500)).family(Family::V6)`.
+#[derive(Clone, Debug)]
+pub struct TestConfig {
+    /// Time to wait for a connection or reply.
+    pub timeout: Duration,
+    /// Local address sockets are bound to.
+    pub source: Option<IpAddr>,
+    /// Network interface sockets are bound to with `SO_BINDTODEVICE` (Linux only).
+    pub interface: Option<String>,
+    /// `SO_MARK` of every socket, for policy routing (Linux only).
+    pub fwmark: Option<u32>,
+    /// VRF device sockets are bound to, so routes of its table are used (Linux only).
+    pub vrf: Option<String>,
+    /// DSCP codepoint of outgoing packets, 0 to 63.
+    pub dscp: Option<u8>,
+    /// ECN codepoint of outgoing packets. The kernel owns the ECN bits of TCP,
+    /// so only UDP and ICMP packets carry it.
+    pub ecn: Option<Ecn>,
+    pub family: Family,
+    /// Length of test payload, in bytes for latency tests and MiB for bandwidth tests.
+    pub size: usize,
+    pub pattern: Pattern,
+}
+
+impl Default for TestConfig {
+    fn default() -> TestConfig {
+        TestConfig {
+            timeout: Duration::from_secs(5),
+            source: None,
+            interface: None,
+            fwmark: None,
+            vrf: None,
+            dscp: None,
+            ecn: None,
+            family: Family::Any,
+            size: 60,
+            pattern: Pattern::Random,
+        }
+    }
+}
+
+impl TestConfig {
+    pub fn new() -> TestConfig {
+        TestConfig::default()
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> TestConfig {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn source(mut self, source: IpAddr) -> TestConfig {
+        self.source = Some(source);
+        self
+    }
+
+    pub fn interface(mut self, interface: &str) -> TestConfig {
+        self.interface = Some(interface.to_string());
+        self
+    }
+
+    pub fn fwmark(mut self, mark: u32) -> TestConfig {
+        self.fwmark = Some(mark);
+        self
+    }
+
+    pub fn vrf(mut self, vrf: &str) -> TestConfig {
+        self.vrf = Some(vrf.to_string());
+        self
+    }
+
+    pub fn dscp(mut self, dscp: u8) -> TestConfig {
+        self.dscp = Some(dscp);
+        self
+    }
+
+    pub fn ecn(mut self, ecn: Ecn) -> TestConfig {
+        self.ecn = Some(ecn);
+        self
+    }
+
+    pub fn family(mut self, family: Family) -> TestConfig {
+        self.family = family;
+        self
+    }
+
+    pub fn size(mut self, size: usize) -> TestConfig {
+        self.size = size;
+        self
+    }
+
+    pub fn pattern(mut self, pattern: Pattern) -> TestConfig {
+        self.pattern = pattern;
+        self
+    }
+
+    /// Whether sockets need options set before connecting.
+    pub(crate) fn binds(&self) -> bool {
+        self.source.is_some()
+            || self.interface.is_some()
+            || self.fwmark.is_some()
+            || self.vrf.is_some()
+            || self.dscp.is_some()
+            || self.ecn.is_some()
+    }
+
+    /// TOS byte or traffic class of outgoing packets, if DSCP or ECN is set.
+    pub(crate) fn tos(&self) -> Result<Option<u8>, Error> {
+        match (self.dscp, self.ecn) {
+            (None, None) => Ok(None),
+            (Some(dscp), _) if dscp > 63 => Err(Error::InvalidInput(format!(
+                "DSCP must be between 0 and 63, got {}",
+                dscp
+            ))),
+            (dscp, ecn) => Ok(Some((dscp.unwrap_or(0) << 2) | ecn.map_or(0, Ecn::bits))),
+        }
+    }
+
+    /// Family targets are resolved to, the one of the source address if it's set.
+    pub(crate) fn resolve_family(&self) -> Family {
+        match (self.family, self.source) {
+            (Family::Any, Some(IpAddr::V4(_))) => Family::V4,
+            (Family::Any, Some(IpAddr::V6(_))) => Family::V6,
+            (family, _) => family,
+        }
+    }
+}
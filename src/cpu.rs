@@ -0,0 +1,167 @@
+// CPU utilisation of the client and the server.
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// CPU time consumed by this process and by the whole host, and when it was sampled.
+#[derive(Clone, Copy, Debug)]
+pub struct CpuTime {
+    user: Duration,
+    system: Duration,
+    /// Non-idle time of all cores of the host.
+    host_busy: Duration,
+    /// Total time of all cores of the host.
+    host_total: Duration,
+    wall: Instant,
+}
+
+#[cfg(target_os = "linux")]
+impl CpuTime {
+    pub fn now() -> CpuTime {
+        let tick = match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+            n if n > 0 => n as u64,
+            _ => 100,
+        };
+        let ticks = |n: u64| Duration::from_micros(n * 1_000_000 / tick);
+        // Fields after the parenthesised command name of /proc/self/stat start at the 3rd,
+        // utime and stime are the 14th and 15th.
+        let stat = std::fs::read_to_string("/proc/self/stat").unwrap_or_default();
+        let fields: Vec<u64> = stat
+            .rsplit(')')
+            .next()
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(|x| x.parse().unwrap_or(0))
+            .collect();
+        let field = |i: usize| fields.get(i - 3).copied().unwrap_or(0);
+        // First line of /proc/stat: "cpu user nice system idle iowait irq softirq steal ..."
+        let host = std::fs::read_to_string("/proc/stat").unwrap_or_default();
+        let host: Vec<u64> = host
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .split_whitespace()
+            .skip(1)
+            .take(8)
+            .map(|x| x.parse().unwrap_or(0))
+            .collect();
+        let idle: u64 = host.iter().skip(3).take(2).sum();
+        let total: u64 = host.iter().sum();
+        CpuTime {
+            user: ticks(field(14)),
+            system: ticks(field(15)),
+            host_busy: ticks(total - idle),
+            host_total: ticks(total),
+            wall: Instant::now(),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl CpuTime {
+    pub fn now() -> CpuTime {
+        let mut usage = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+        unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) };
+        let usage = unsafe { usage.assume_init() };
+        let time = |t: libc::timeval| {
+            Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+        };
+        CpuTime {
+            user: time(usage.ru_utime),
+            system: time(usage.ru_stime),
+            host_busy: Duration::default(),
+            host_total: Duration::default(),
+            wall: Instant::now(),
+        }
+    }
+}
+
+impl CpuTime {
+    /// CPU utilisation since this sample was taken.
+    pub fn usage(&self) -> CpuUsage {
+        let now = CpuTime::now();
+        let wall = (now.wall - self.wall).as_secs_f64();
+        let percent = |t: Duration| {
+            if wall > 0.0 {
+                100.0 * t.as_secs_f64() / wall
+            } else {
+                0.0
+            }
+        };
+        let host_total = now.host_total.saturating_sub(self.host_total).as_secs_f64();
+        CpuUsage {
+            user: percent(now.user.saturating_sub(self.user)),
+            system: percent(now.system.saturating_sub(self.system)),
+            host: if host_total > 0.0 {
+                100.0 * now.host_busy.saturating_sub(self.host_busy).as_secs_f64() / host_total
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+/// CPU utilisation of a bandwidth test.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CpuUsage {
+    /// Percent of one core spent by the process in user space.
+    pub user: f64,
+    /// Percent of one core spent by the process in the kernel.
+    pub system: f64,
+    /// Percent of all cores of the host that were busy.
+    pub host: f64,
+}
+
+impl CpuUsage {
+    /// The `CPU <user> <system> <host>` part of a server reply.
+    pub fn reply(&self) -> String {
+        format!("CPU {:.1} {:.1} {:.1}", self.user, self.system, self.host)
+    }
+
+    /// Parse the `CPU <user> <system> <host>` part of a server reply.
+    pub fn from_reply(line: &str) -> Option<CpuUsage> {
+        let mut words = line.split_whitespace().skip_while(|&w| w != "CPU").skip(1);
+        let mut next = || -> Option<f64> { words.next()?.parse().ok() };
+        Some(CpuUsage {
+            user: next()?,
+            system: next()?,
+            host: next()?,
+        })
+    }
+
+    /// Average of `samples`, `None` without any.
+    pub(crate) fn average(samples: &[CpuUsage]) -> Option<CpuUsage> {
+        if samples.is_empty() {
+            return None;
+        }
+        let n = samples.len() as f64;
+        Some(CpuUsage {
+            user: samples.iter().map(|c| c.user).sum::<f64>() / n,
+            system: samples.iter().map(|c| c.system).sum::<f64>() / n,
+            host: samples.iter().map(|c| c.host).sum::<f64>() / n,
+        })
+    }
+}
+
+impl fmt::Display for CpuUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:.1}% ({:.1}% user, {:.1}% system), host {:.1}%",
+            self.user + self.system,
+            self.user,
+            self.system,
+            self.host
+        )
+    }
+}
+
+#[test]
+fn from_reply() {
+    let cpu = CpuUsage::from_reply("Ok 1048575 123456 CPU 12.5 30.0 41.2\n").unwrap();
+    assert_eq!((cpu.user, cpu.system, cpu.host), (12.5, 30.0, 41.2));
+    let reply = CpuUsage::from_reply(&cpu.reply()).unwrap();
+    assert_eq!((reply.user, reply.system, reply.host), (12.5, 30.0, 41.2));
+    assert!(CpuUsage::from_reply("Ok 1048575 123456\n").is_none());
+    assert!(CpuUsage::from_reply("CPU 12.5 x 41.2").is_none());
+    assert!(CpuUsage::from_reply("ERROR Unknown command\n").is_none());
+}
@@ -0,0 +1,151 @@
+use std::fmt;
+use std::io;
+
+/// Errors of nettest tasks, classified so callers don't need to match on messages.
+#[derive(Debug)]
+pub enum Error {
+    /// The target couldn't be resolved to an address.
+    Resolve(String),
+    /// No reply within the timeout.
+    Timeout,
+    /// Not allowed to open the socket or set an option, e.g. ICMP sockets without privileges.
+    PermissionDenied(String),
+    /// The peer doesn't speak the protocol, or closed the connection in the middle.
+    Protocol(String),
+    /// A reply arrived but isn't the one expected.
+    UnexpectedReply(String),
+    /// The task was configured with values it can't use.
+    InvalidInput(String),
+    /// Any other I/O error, with what was being done.
+    Io(String, io::Error),
+    /// Error of the QUIC library, with what was being done.
+    Quic(String, quiche::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Prefix the message with `context`, timeouts stay as they are.
+    pub(crate) fn context<C: fmt::Display>(self, context: C) -> Error {
+        let join = |msg: String| {
+            if msg.is_empty() {
+                context.to_string()
+            } else {
+                format!("{}: {}", context, msg)
+            }
+        };
+        match self {
+            Error::Resolve(msg) => Error::Resolve(join(msg)),
+            Error::Timeout => Error::Timeout,
+            Error::PermissionDenied(msg) => Error::PermissionDenied(join(msg)),
+            Error::Protocol(msg) => Error::Protocol(join(msg)),
+            Error::UnexpectedReply(msg) => Error::UnexpectedReply(join(msg)),
+            Error::InvalidInput(msg) => Error::InvalidInput(join(msg)),
+            Error::Io(msg, e) => Error::Io(join(msg), e),
+            Error::Quic(msg, e) => Error::Quic(join(msg), e),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Timeout => write!(f, "Timed out"),
+            Error::Resolve(msg)
+            | Error::PermissionDenied(msg)
+            | Error::Protocol(msg)
+            | Error::UnexpectedReply(msg)
+            | Error::InvalidInput(msg) => write!(f, "{}", msg),
+            Error::Io(msg, e) if msg.is_empty() => write!(f, "{}", e),
+            Error::Io(msg, e) => write!(f, "{}: {}", msg, e),
+            Error::Quic(msg, e) if msg.is_empty() => write!(f, "QUIC error: {}", e),
+            Error::Quic(msg, e) => write!(f, "{}: QUIC error: {}", msg, e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(_, e) => Some(e),
+            Error::Quic(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        match e.kind() {
+            // Sockets with a timeout report it as either, depending on the platform.
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Error::Timeout,
+            io::ErrorKind::PermissionDenied => Error::PermissionDenied(e.to_string()),
+            _ => Error::Io(String::new(), e),
+        }
+    }
+}
+
+impl From<quiche::Error> for Error {
+    fn from(e: quiche::Error) -> Error {
+        Error::Quic(String::new(), e)
+    }
+}
+
+/// Add what was being done to an error, like `anyhow::Context`.
+pub(crate) trait Context<T> {
+    fn context<C: fmt::Display>(self, context: C) -> Result<T>;
+    fn with_context<C: fmt::Display, F: FnOnce() -> C>(self, f: F) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> Context<T> for std::result::Result<T, E> {
+    fn context<C: fmt::Display>(self, context: C) -> Result<T> {
+        self.map_err(|e| e.into().context(context))
+    }
+
+    fn with_context<C: fmt::Display, F: FnOnce() -> C>(self, f: F) -> Result<T> {
+        self.map_err(|e| e.into().context(f()))
+    }
+}
+
+#[test]
+fn io_kinds() {
+    let timeout = io::Error::new(io::ErrorKind::WouldBlock, "would block");
+    assert!(matches!(Error::from(timeout), Error::Timeout));
+    let timeout = io::Error::new(io::ErrorKind::TimedOut, "timed out");
+    assert!(matches!(Error::from(timeout), Error::Timeout));
+    let denied = io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+    assert!(matches!(Error::from(denied), Error::PermissionDenied(_)));
+    let refused = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+    match Error::from(refused) {
+        Error::Io(msg, e) => {
+            assert!(msg.is_empty());
+            assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+        }
+        e => panic!("Unexpected error {:?}", e),
+    }
+}
+
+#[test]
+fn context() {
+    let refused: Result<()> = Err(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"))
+        .context("Failed to connect");
+    assert_eq!(
+        refused.unwrap_err().to_string(),
+        "Failed to connect: refused"
+    );
+    let denied: Result<()> = Err(Error::PermissionDenied("denied".into()))
+        .with_context(|| "Failed to bind")
+        .context("Ping");
+    match denied {
+        Err(Error::PermissionDenied(msg)) => assert_eq!(msg, "Ping: Failed to bind: denied"),
+        e => panic!("Unexpected result {:?}", e),
+    }
+    let timeout: Result<()> = Err(Error::Timeout).context("Ping");
+    assert!(matches!(timeout, Err(Error::Timeout)));
+    assert_eq!(
+        Error::Io("".into(), io::Error::new(io::ErrorKind::Other, "closed"))
+            .context("Read")
+            .to_string(),
+        "Read: closed"
+    );
+}
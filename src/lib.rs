@@ -1,25 +1,153 @@
+mod asynctask;
+mod bufferbloat;
+mod config;
+mod cpu;
+mod error;
+pub mod http;
+mod marking;
+mod metrics;
+mod monitor;
 mod ping;
+mod profile;
+mod quic;
+mod quicdgram;
 mod quicdownload;
+mod quicping;
+mod rpm;
+mod socket;
+mod stats;
+mod sweep;
+mod tcpbidir;
 mod tcpdownload;
+mod tcpinfo;
 mod tcping;
 mod tcpupload;
 mod udping;
+mod udpload;
+pub mod wire;
+mod zerocopy;
 
+pub use asynctask::{
+    AsyncPingTask, AsyncTask, AsyncTcpingTask, AsyncUdpingTask, Blocking, TaskFuture, Threaded,
+};
+pub use bufferbloat::BufferbloatTask;
+pub use config::{Ecn, Family, Pattern, TestConfig};
+pub use cpu::{CpuTime, CpuUsage};
+pub use error::{Error, Result};
+pub use marking::{Change, Marking, MarkingTask};
+pub use metrics::Metrics;
+pub use monitor::{Alert, Monitor, Target, Threshold};
 pub use ping::PingTask;
+pub use profile::{Profile, ProfileTask};
+pub use quic::QuicOptions;
+pub use quicdgram::QuicdgramTask;
 pub use quicdownload::QuicdownloadTask;
+pub use quicping::QuicpingTask;
+pub use rpm::RpmTask;
+pub use socket::{set_congestion, TcpOptions};
+pub use stats::{Latency, Speed, Stats};
+pub use sweep::{Sweep, SweepResult};
+pub use tcpbidir::TcpbidirTask;
 pub use tcpdownload::TcpdownloadTask;
+pub use tcpinfo::TcpInfo;
 pub use tcping::TcpingTask;
 pub use tcpupload::TcpuploadTask;
 pub use udping::UdpingTask;
+pub use udpload::UdploadTask;
+pub use zerocopy::RandPool;
+
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
 
 const MB: usize = 1024 * 1024;
 
 pub trait Task {
-    fn run(&mut self) -> anyhow::Result<Measurement>;
+    fn run(&mut self) -> Result<Measurement>;
+    /// Log statistics only the task itself keeps track of, after all runs.
+    fn summary(&self) {}
+}
+
+impl<T: Task + ?Sized> Task for Box<T> {
+    fn run(&mut self) -> Result<Measurement> {
+        (**self).run()
+    }
+
+    fn summary(&self) {
+        (**self).summary()
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum Measurement {
-    Time(std::time::Duration),
-    Speed(usize, std::time::Duration),
+    Time(Probe),
+    Speed(Transfer),
+}
+
+impl Measurement {
+    pub fn target(&self) -> SocketAddr {
+        match self {
+            Measurement::Time(probe) => probe.target,
+            Measurement::Speed(transfer) => transfer.target,
+        }
+    }
+
+    /// Round-trip time of a probe or duration of a transfer.
+    pub fn time(&self) -> Duration {
+        match self {
+            Measurement::Time(probe) => probe.time,
+            Measurement::Speed(transfer) => transfer.time,
+        }
+    }
+}
+
+/// Result of one latency probe.
+#[derive(Clone, Debug)]
+pub struct Probe {
+    pub target: SocketAddr,
+    pub seq: u64,
+    /// Payload size of the probe.
+    pub bytes: usize,
+    /// When the probe was sent.
+    pub sent: SystemTime,
+    pub time: Duration,
+}
+
+impl Probe {
+    /// Probe that took `time` until now.
+    pub fn new(target: SocketAddr, seq: u64, bytes: usize, time: Duration) -> Probe {
+        Probe {
+            target,
+            seq,
+            bytes,
+            sent: SystemTime::now() - time,
+            time,
+        }
+    }
+}
+
+/// Result of one bandwidth test.
+#[derive(Clone, Debug)]
+pub struct Transfer {
+    pub target: SocketAddr,
+    pub bytes: usize,
+    /// When the transfer started.
+    pub start: SystemTime,
+    pub time: Duration,
+}
+
+impl Transfer {
+    /// Transfer that took `time` until now.
+    pub fn new(target: SocketAddr, bytes: usize, time: Duration) -> Transfer {
+        Transfer {
+            target,
+            bytes,
+            start: SystemTime::now() - time,
+            time,
+        }
+    }
+
+    /// Megabits per second.
+    pub fn mbps(&self) -> f64 {
+        self.bytes as f64 * 8.0 / self.time.as_micros().max(1) as f64
+    }
 }
@@ -2,6 +2,8 @@ use anyhow::{anyhow, Result};
 use clap::Clap;
 use log::*;
 use nettest::*;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
 use std::time::Duration;
 
 static LOGGER: Logger = Logger;
@@ -40,6 +42,33 @@ struct Opt {
     /// The unit is byte in ping test and Megabyte in bandwidth test.
     #[clap(global = true, short, long, default_value = "60")]
     size: usize,
+    /// Content of test payload: random, zero or a byte like 0xff.
+    #[clap(global = true, long, default_value = "random")]
+    pattern: Pattern,
+    /// Only use IPv4 addresses of the target.
+    #[clap(global = true, short = '4', long)]
+    ipv4: bool,
+    /// Only use IPv6 addresses of the target.
+    #[clap(global = true, short = '6', long, conflicts_with = "ipv4")]
+    ipv6: bool,
+    /// Local address to send from.
+    #[clap(global = true, long)]
+    source: Option<IpAddr>,
+    /// Network interface to send through (SO_BINDTODEVICE, Linux only).
+    #[clap(global = true, short = 'I', long)]
+    interface: Option<String>,
+    /// Firewall mark (SO_MARK) of every socket, in decimal or 0x hex, for policy routing.
+    #[clap(global = true, long, parse(try_from_str = parse_fwmark))]
+    fwmark: Option<u32>,
+    /// Run tests inside this VRF (Linux only).
+    #[clap(global = true, long, conflicts_with = "interface")]
+    vrf: Option<String>,
+    /// DSCP codepoint of outgoing packets, 0 to 63.
+    #[clap(global = true, long)]
+    dscp: Option<u8>,
+    /// ECN codepoint of outgoing UDP and ICMP packets: not-ect, ect0, ect1 or ce.
+    #[clap(global = true, long)]
+    ecn: Option<Ecn>,
 }
 
 #[derive(Debug, Clap)]
@@ -47,14 +76,43 @@ pub enum Command {
     /// Measuring latency using ICMP or ICMPv6 echo"
     /// example: `nettest ping 127.0.0.1` or `nettest ping google.com`
     Ping {
-        /// IP or hostname of target.
-        address: String,
+        /// IPs, hostnames or CIDR ranges like 192.0.2.0/24 of targets.
+        /// Several targets are pinged concurrently and summarised in a table.
+        addresses: Vec<String>,
+        /// File of more targets, separated by whitespace or lines.
+        #[clap(long, parse(from_os_str))]
+        file: Option<PathBuf>,
+        /// Number of targets pinged at the same time.
+        #[clap(long, default_value = "64")]
+        concurrency: usize,
     },
     /// Measuring latency of TCP shake hands
     /// example: `nettest tcping 127.0.0.1:8080` or `nettest ping github.com:443`
     Tcping {
         /// IP or hostname of target.
         address: String,
+        #[clap(flatten)]
+        tcp: TcpOpt,
+    },
+    /// Measuring latency of QUIC handshake
+    /// example: `nettest quicping 127.0.0.1:8080`
+    Quicping {
+        /// IP or hostname of target.
+        address: String,
+        /// Resume the session of the previous handshake and measure the reply
+        /// to a request sent as 0-RTT early data.
+        #[clap(long = "0rtt")]
+        zero_rtt: bool,
+        #[clap(flatten)]
+        quic: QuicOpt,
+    },
+    /// Measuring latency and loss using QUIC DATAGRAM frames echoed by nettest server
+    /// example: `nettest quicdgram 127.0.0.1:8080`
+    Quicdgram {
+        /// IP or hostname of target.
+        address: String,
+        #[clap(flatten)]
+        quic: QuicOpt,
     },
     /// Measuring latency using UDP echo. use `socat -v UDP-LISTEN:8000,fork PIPE` to start a server"
     /// example: `nettest udping 127.0.0.1:8000`
@@ -62,10 +120,102 @@ pub enum Command {
         /// IP or hostname of target.
         address: String,
     },
+    /// Checking whether the path keeps DSCP and ECN, reported by nettest server
+    /// example: `nettest marking --dscp 46 --ecn ect0 127.0.0.1:8080`
+    Marking {
+        /// IP or hostname of nettest server.
+        address: String,
+    },
+    /// Running the tests of a config file forever, alerting when loss or latency is too high
+    /// example: `nettest monitor nettest.conf`
+    Monitor {
+        /// One test per line: `<test> <address> [interval=60] [window=20] [max-loss=<%>]
+        /// [max-rtt=<ms>] [min-speed=<Mbps>]`
+        #[clap(parse(from_os_str))]
+        config: PathBuf,
+        /// Shift every run randomly by up to this percent of its interval.
+        #[clap(long, default_value = "10")]
+        jitter: f64,
+        /// Serve Prometheus metrics of the results at http://<addr>/metrics, e.g. 0.0.0.0:9100.
+        #[clap(long)]
+        metrics: Option<SocketAddr>,
+    },
     /// Measuring TCP upload bandwidth.
     Tcpupload {
         /// IP or hostname of target.
         address: String,
+        #[clap(flatten)]
+        tcp: TcpOpt,
+        /// Send with sendfile(2) from a memfd, so the client's CPU doesn't limit the result.
+        #[clap(long)]
+        zerocopy: bool,
+    },
+    /// Measuring TCP download and upload bandwidth at the same time.
+    Tcpbidir {
+        /// IP or hostname of target.
+        address: String,
+        #[clap(flatten)]
+        tcp: TcpOpt,
+    },
+    /// Measuring latency under load (bufferbloat): probes while idle and during a TCP download
+    /// example: `nettest bufferbloat 127.0.0.1:8080`
+    Bufferbloat {
+        /// IP or hostname of nettest server to load the link with.
+        address: String,
+        /// Protocol of latency probes.
+        #[clap(long, default_value = "tcp", possible_values = &["icmp", "udp", "tcp"])]
+        probe: String,
+        /// Target of latency probes, the load server by default.
+        /// udp probes need an echo server.
+        #[clap(long)]
+        probe_address: Option<String>,
+        /// Wait interval ms between latency probes.
+        #[clap(long, default_value = "100")]
+        probe_interval: u64,
+        /// Load the link with an upload instead of a download.
+        #[clap(long)]
+        upload: bool,
+        #[clap(flatten)]
+        tcp: TcpOpt,
+    },
+    /// Measuring responsiveness under working conditions in Round-trips Per Minute
+    /// example: `nettest rpm 127.0.0.1:8080`
+    Rpm {
+        /// IP or hostname of nettest server.
+        address: String,
+        #[clap(flatten)]
+        tcp: TcpOpt,
+    },
+    /// Measuring UDP throughput, loss, reordering and jitter at a target bitrate
+    /// example: `nettest udpload --bitrate 10 127.0.0.1:8080`
+    Udpload {
+        /// IP or hostname of nettest server.
+        address: String,
+        /// Target bitrate in Mbps.
+        #[clap(long, default_value = "1")]
+        bitrate: f64,
+        /// Size of every datagram in bytes.
+        #[clap(long, default_value = "1200")]
+        length: usize,
+        /// Duration of each test in seconds.
+        #[clap(long, default_value = "10")]
+        duration: u64,
+    },
+    /// Replaying VoIP, video or game traffic to the nettest server, reporting MOS of voice
+    /// and frame latency of video example: `nettest profile --profile voip-g711 127.0.0.1:8080`
+    Profile {
+        /// IP or hostname of nettest server.
+        address: String,
+        /// Traffic pattern to replay.
+        #[clap(
+            long,
+            default_value = "voip-g711",
+            possible_values = &["voip-g711", "voip-opus", "video", "game"]
+        )]
+        profile: nettest::Profile,
+        /// Duration of each test in seconds.
+        #[clap(long, default_value = "10")]
+        duration: u64,
     },
     /// Measuring QUIC upload bandwidth.
     Quicupload {
@@ -76,14 +226,132 @@ pub enum Command {
     Tcpdownload {
         /// IP or hostname of target.
         address: String,
+        #[clap(flatten)]
+        tcp: TcpOpt,
     },
     /// Measuring QUIC download bandwidth.
     Quicdownload {
         /// IP or hostname of target.
         address: String,
+        /// Establish a new connection for every test, including the handshake in the result.
+        #[clap(long)]
+        reconnect: bool,
+        #[clap(flatten)]
+        quic: QuicOpt,
     },
 }
 
+#[derive(Debug, Clap)]
+pub struct TcpOpt {
+    /// TCP congestion control algorithm, e.g. cubic, bbr or reno.
+    /// In download test the server is asked to use it.
+    #[clap(long)]
+    congestion: Option<String>,
+    /// Size of socket send buffer (SO_SNDBUF) in bytes.
+    #[clap(long)]
+    sndbuf: Option<usize>,
+    /// Size of socket receive buffer (SO_RCVBUF) in bytes.
+    #[clap(long)]
+    rcvbuf: Option<usize>,
+    /// Disable Nagle's algorithm (TCP_NODELAY).
+    #[clap(long)]
+    nodelay: bool,
+    /// Maximum segment size (TCP_MAXSEG) in bytes.
+    #[clap(long)]
+    mss: Option<u32>,
+    /// Maximum pacing rate (SO_MAX_PACING_RATE) in Mbps.
+    #[clap(long)]
+    max_pacing_rate: Option<f64>,
+}
+
+impl From<TcpOpt> for TcpOptions {
+    fn from(opt: TcpOpt) -> TcpOptions {
+        TcpOptions {
+            congestion: opt.congestion,
+            send_buffer: opt.sndbuf,
+            recv_buffer: opt.rcvbuf,
+            nodelay: opt.nodelay,
+            mss: opt.mss,
+            max_pacing_rate: opt.max_pacing_rate.map(|r| (r * 1_000_000.0 / 8.0) as u64),
+        }
+    }
+}
+
+#[derive(Debug, Clap)]
+pub struct QuicOpt {
+    /// Don't verify the server certificate.
+    #[clap(long)]
+    insecure: bool,
+    /// PEM file of CA certificates to verify the server with, instead of the system roots.
+    #[clap(long, parse(from_os_str))]
+    ca_file: Option<PathBuf>,
+    /// Only accept the server certificate with this SHA-256 fingerprint (hex, `:` is optional).
+    #[clap(long, parse(try_from_str = parse_fingerprint))]
+    pin_sha256: Option<Fingerprint>,
+    /// Server name used in SNI and certificate verification.
+    #[clap(long)]
+    server_name: Option<String>,
+    /// QUIC connection flow-control window in bytes.
+    #[clap(long, default_value = "67108864")]
+    max_data: u64,
+    /// QUIC flow-control window of each stream in bytes.
+    #[clap(long, default_value = "16777216")]
+    max_stream_data: u64,
+    /// Number of concurrent QUIC streams the peer may open.
+    #[clap(long, default_value = "100")]
+    max_streams: u64,
+    /// Maximum UDP payload size of QUIC packets.
+    #[clap(long, default_value = "1350")]
+    max_udp_payload: usize,
+    /// QUIC congestion control algorithm: reno or cubic.
+    #[clap(long, default_value = "cubic")]
+    cc: String,
+    /// Directory to write qlog traces of QUIC connections to.
+    #[clap(long, parse(from_os_str))]
+    qlog: Option<PathBuf>,
+}
+
+impl From<QuicOpt> for QuicOptions {
+    fn from(opt: QuicOpt) -> QuicOptions {
+        QuicOptions {
+            insecure: opt.insecure,
+            ca_file: opt.ca_file,
+            pin_sha256: opt.pin_sha256.map(|f| f.0),
+            server_name: opt.server_name,
+            max_data: opt.max_data,
+            max_stream_data: opt.max_stream_data,
+            max_streams: opt.max_streams,
+            max_udp_payload: opt.max_udp_payload,
+            cc: opt.cc,
+            qlog_dir: opt.qlog,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Fingerprint(Vec<u8>);
+
+fn parse_fingerprint(s: &str) -> Result<Fingerprint> {
+    let hex: String = s.chars().filter(|&c| c != ':').collect();
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("SHA-256 fingerprint should be 32 bytes in hex"));
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect();
+    Ok(Fingerprint(bytes))
+}
+
+fn parse_fwmark(s: &str) -> Result<u32> {
+    let mark = if s.starts_with("0x") {
+        u32::from_str_radix(&s[2..], 16)
+    } else {
+        s.parse()
+    };
+    mark.map_err(|_| anyhow!("Invalid fwmark {}", s))
+}
+
 fn main() {
     let opt = Opt::parse();
     log::set_logger(&LOGGER).expect("Set logger failed");
@@ -98,16 +366,176 @@ fn run(opt: Opt) -> Result<()> {
     if opt.count == Some(0) {
         return Err(anyhow!("count = 0 means don't run anything"));
     }
+    let family = if opt.ipv4 {
+        Family::V4
+    } else if opt.ipv6 {
+        Family::V6
+    } else {
+        Family::Any
+    };
+    let config = TestConfig {
+        timeout: Duration::from_secs(opt.timeout),
+        source: opt.source,
+        interface: opt.interface,
+        fwmark: opt.fwmark,
+        vrf: opt.vrf,
+        dscp: opt.dscp,
+        ecn: opt.ecn,
+        family,
+        size: opt.size,
+        pattern: opt.pattern,
+        ..TestConfig::default()
+    };
     use Command::*;
     let mut task: Box<dyn Task> = match opt.cmd {
-        Ping { address } => Box::new(PingTask::new(&address, opt.size, opt.timeout)?),
-        Tcping { address } => Box::new(TcpingTask::new(&address, opt.timeout)?),
-        Udping { address } => Box::new(UdpingTask::new(&address, opt.size, opt.timeout)?),
-        Tcpupload { address } => Box::new(TcpuploadTask::new(&address, opt.size, opt.timeout)?),
-        Tcpdownload { address } => Box::new(TcpdownloadTask::new(&address, opt.size, opt.timeout)?),
-        Quicdownload { address } => {
-            Box::new(QuicdownloadTask::new(&address, opt.size, opt.timeout)?)
+        Ping {
+            addresses,
+            file,
+            concurrency,
+        } => {
+            let mut targets = Vec::new();
+            for spec in &addresses {
+                targets.extend(Sweep::expand(spec)?);
+            }
+            if let Some(path) = file {
+                targets.extend(Sweep::read_targets(&path)?);
+            }
+            match targets.len() {
+                0 => return Err(anyhow!("No target to ping")),
+                1 => Box::new(PingTask::new(&targets[0], &config)?),
+                _ => {
+                    let results = Sweep::new(targets, &config)
+                        .count(opt.count.unwrap_or(5))
+                        .interval(Duration::from_millis(opt.interval))
+                        .concurrency(concurrency)
+                        .run();
+                    Sweep::log_table(&results);
+                    return Ok(());
+                }
+            }
+        }
+        Tcping { address, tcp } => Box::new(TcpingTask::new(&address, &config, &tcp.into())?),
+        Quicping {
+            address,
+            zero_rtt,
+            quic,
+        } => Box::new(QuicpingTask::new(&address, &config, &quic.into())?.zero_rtt(zero_rtt)),
+        Quicdgram { address, quic } => {
+            Box::new(QuicdgramTask::new(&address, &config, &quic.into())?)
         }
+        Udping { address } => Box::new(UdpingTask::new(&address, &config)?),
+        Command::Monitor {
+            config: path,
+            jitter,
+            metrics,
+        } => {
+            let mut monitor = nettest::Monitor::load(&path, &config)?.jitter(jitter / 100.0);
+            if let Some(addr) = metrics {
+                let metrics = Metrics::new();
+                metrics.serve(addr)?;
+                info!("Serving metrics at http://{}/metrics", addr);
+                monitor = monitor.metrics(&metrics);
+            }
+            monitor.run(|alert| {
+                if alert.raised {
+                    warn!("ALERT {}", alert);
+                } else {
+                    info!("RECOVERED {}", alert);
+                }
+            });
+            return Ok(());
+        }
+        Command::Marking { address } => Box::new(MarkingTask::new(&address, &config)?),
+        Tcpupload {
+            address,
+            tcp,
+            zerocopy,
+        } => Box::new(TcpuploadTask::new(
+            &address,
+            &config,
+            &tcp.into(),
+            zerocopy,
+        )?),
+        Tcpbidir { address, tcp } => Box::new(TcpbidirTask::new(&address, &config, &tcp.into())?),
+        Bufferbloat {
+            address,
+            probe,
+            probe_address,
+            probe_interval,
+            upload,
+            tcp,
+        } => {
+            let tcp: TcpOptions = tcp.into();
+            let probe_address = probe_address.unwrap_or_else(|| address.clone());
+            const PROBE_SIZE: usize = 64;
+            let probe_config = config.clone().size(PROBE_SIZE);
+            let probe: Box<dyn AsyncTask> = match probe.as_str() {
+                "icmp" => {
+                    // Ping takes a host, drop the port of the load server.
+                    let host = match probe_address.to_socket_addrs() {
+                        Ok(mut addrs) => addrs.next().map(|a| a.ip().to_string()),
+                        Err(_) => None,
+                    };
+                    let host = host.unwrap_or(probe_address);
+                    Box::new(Threaded::new(PingTask::new(&host, &probe_config)?))
+                }
+                "udp" => Box::new(async_std::task::block_on(AsyncUdpingTask::new(
+                    &probe_address,
+                    &probe_config,
+                ))?),
+                _ => Box::new(async_std::task::block_on(AsyncTcpingTask::new(
+                    &probe_address,
+                    &probe_config,
+                    &tcp,
+                ))?),
+            };
+            let load: Box<dyn Task + Send> = if upload {
+                Box::new(TcpuploadTask::new(&address, &config, &tcp, false)?)
+            } else {
+                Box::new(TcpdownloadTask::new(&address, &config, &tcp)?)
+            };
+            Box::new(BufferbloatTask::new(
+                probe,
+                load,
+                Duration::from_millis(probe_interval),
+            ))
+        }
+        Rpm { address, tcp } => Box::new(RpmTask::new(&address, &config, &tcp.into())?),
+        Udpload {
+            address,
+            bitrate,
+            length,
+            duration,
+        } => Box::new(UdploadTask::new(
+            &address,
+            &config,
+            bitrate,
+            length,
+            Duration::from_secs(duration),
+        )?),
+        Command::Profile {
+            address,
+            profile,
+            duration,
+        } => Box::new(ProfileTask::new(
+            &address,
+            &config,
+            profile,
+            Duration::from_secs(duration),
+        )?),
+        Tcpdownload { address, tcp } => {
+            Box::new(TcpdownloadTask::new(&address, &config, &tcp.into())?)
+        }
+        Quicdownload {
+            address,
+            reconnect,
+            quic,
+        } => Box::new(QuicdownloadTask::new(
+            &address,
+            &config,
+            reconnect,
+            &quic.into(),
+        )?),
         _ => todo!(),
     };
     let count = opt.count.unwrap_or(5);
@@ -124,6 +552,7 @@ fn run(opt: Opt) -> Result<()> {
         std::thread::sleep(Duration::from_millis(opt.interval));
     }
     analisys(results, err_count);
+    task.summary();
     Ok(())
 }
 
@@ -133,78 +562,5 @@ fn analisys(results: Vec<Measurement>, err_count: usize) {
         return;
     }
     info!("--- statistics ---");
-    match results[0] {
-        Measurement::Speed(_l, _t) => {
-            let speeds: Vec<(usize, Duration)> = results
-                .iter()
-                .map(|s| {
-                    if let Measurement::Speed(l, t) = s {
-                        (*l, *t)
-                    } else {
-                        unreachable!()
-                    }
-                })
-                .collect();
-            let min = speeds
-                .iter()
-                .min_by(|x, y| (x.0 as u32 * y.1).cmp(&(y.0 as u32 * x.1)))
-                .unwrap();
-            let max = speeds
-                .iter()
-                .max_by(|x, y| (x.0 as u32 * y.1).cmp(&(y.0 as u32 * x.1)))
-                .unwrap();
-            let lens: usize = speeds.iter().map(|x| x.0).sum();
-            let times: Duration = speeds.iter().map(|x| x.1).sum();
-            let speed = |x: &(usize, Duration)| (x.0 * 8) as f64 / (x.1.as_micros() as f64);
-            info!("{} MiB transmitted in {:?}", lens / (1024 * 1024), times);
-            info!(
-                "Speed min/avg/max {:.3}/{:.3}/{:.3}Mbps",
-                speed(min),
-                speed(&(lens, times)),
-                speed(max)
-            );
-        }
-        Measurement::Time(_t) => {
-            let times: Vec<Duration> = results
-                .iter()
-                .map(|t| {
-                    if let Measurement::Time(t) = t {
-                        *t
-                    } else {
-                        unreachable!()
-                    }
-                })
-                .collect();
-            let count = times.len() + err_count;
-            let received = times.len();
-            let total: Duration = times.iter().sum();
-            let min = *times.iter().min().unwrap();
-            let max = *times.iter().max().unwrap();
-            let avg = total / (received as u32);
-            let ms = |t: Duration| format!("{:.3}", t.as_micros() as f64 / 1000.0);
-            let mdev = ((times
-                .iter()
-                .map(|t| t.as_nanos() as i128)
-                .map(|t| t - (avg.as_nanos() as i128))
-                .map(|t| t * t)
-                .sum::<i128>()) as f64
-                / received as f64)
-                .sqrt()
-                / 1_000_000.0;
-            info!(
-                "{} packets transmitted, {} received, {:.2}% packet loss, time {} ms",
-                count,
-                received,
-                100.0 * err_count as f64 / (count as f64),
-                ms(total)
-            );
-            info!(
-                "rtt min/avg/max/mdev = {}/{}/{}/{:.3} ms",
-                ms(min),
-                ms(avg),
-                ms(max),
-                mdev
-            );
-        }
-    }
+    Stats::new(&results, err_count).log();
 }
@@ -0,0 +1,270 @@
+use crate::config::{Ecn, TestConfig};
+use crate::error::{Context, Error, Result};
+use crate::socket::{self, TcpOptions};
+use crate::wire::{self, MARK, MARK_LEN};
+use crate::{Measurement, Probe, Task};
+use log::{info, warn};
+use rand::{Rng, SeedableRng};
+use rand_xoshiro::Xoshiro256Plus;
+use std::fmt;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// DSCP and ECN of a packet, the TOS byte of IPv4 or traffic class of IPv6.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Marking {
+    pub dscp: u8,
+    pub ecn: Ecn,
+}
+
+impl Marking {
+    pub fn from_tos(tos: u8) -> Marking {
+        Marking {
+            dscp: tos >> 2,
+            ecn: Ecn::from_tos(tos),
+        }
+    }
+
+    pub fn tos(self) -> u8 {
+        (self.dscp << 2) | self.ecn.bits()
+    }
+}
+
+/// Name of the well-known DSCP codepoints.
+fn dscp_name(dscp: u8) -> Option<String> {
+    match dscp {
+        1 => Some("LE".into()),
+        44 => Some("VOICE-ADMIT".into()),
+        46 => Some("EF".into()),
+        _ if dscp % 8 == 0 => Some(format!("CS{}", dscp / 8)),
+        // AFxy is x << 3 | y << 1 for classes 1 to 4 and drop precedences 1 to 3.
+        10..=38 if dscp % 2 == 0 => Some(format!("AF{}{}", dscp / 8, dscp % 8 / 2)),
+        _ => None,
+    }
+}
+
+impl fmt::Display for Marking {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match dscp_name(self.dscp) {
+            Some(name) => write!(f, "DSCP {} ({}), {}", self.dscp, name, self.ecn),
+            None => write!(f, "DSCP {}, {}", self.dscp, self.ecn),
+        }
+    }
+}
+
+/// What the path did to a codepoint.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Change {
+    Preserved,
+    /// Cleared to DSCP 0 or Not-ECT.
+    Bleached,
+    /// Changed to another codepoint.
+    Remarked,
+    /// ECT changed to CE by a congested router, which is not remarking.
+    CongestionExperienced,
+}
+
+impl Change {
+    pub fn dscp(sent: u8, received: u8) -> Change {
+        match received {
+            _ if received == sent => Change::Preserved,
+            0 => Change::Bleached,
+            _ => Change::Remarked,
+        }
+    }
+
+    pub fn ecn(sent: Ecn, received: Ecn) -> Change {
+        match (sent, received) {
+            _ if received == sent => Change::Preserved,
+            (_, Ecn::NotEct) => Change::Bleached,
+            (Ecn::Ect0, Ecn::Ce) | (Ecn::Ect1, Ecn::Ce) => Change::CongestionExperienced,
+            _ => Change::Remarked,
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Preserved => write!(f, "preserved"),
+            Change::Bleached => write!(f, "bleached"),
+            Change::Remarked => write!(f, "remarked"),
+            Change::CongestionExperienced => write!(f, "congestion experienced"),
+        }
+    }
+}
+
+/// Send marked UDP probes and TCP handshakes to the nettest server, which
+/// reports the DSCP and ECN they arrived with.
+pub struct MarkingTask {
+    target: SocketAddr,
+    socket: UdpSocket,
+    config: TestConfig,
+    sent: Marking,
+    id: u64,
+    seq: u64,
+    /// Marking every UDP probe and TCP connection arrived with.
+    udp: Vec<Marking>,
+    tcp: Vec<Marking>,
+}
+
+impl MarkingTask {
+    pub fn new(addr: &str, config: &TestConfig) -> Result<MarkingTask> {
+        let target = socket::resolve(addr, config)?;
+        let socket = socket::udp_connect(&target, config)?;
+        let sent = Marking::from_tos(config.tos()?.unwrap_or(0));
+        info!("Check markings to {}, sending {}", target, sent);
+        Ok(MarkingTask {
+            target,
+            socket,
+            config: config.clone(),
+            sent,
+            id: Xoshiro256Plus::from_entropy().gen(),
+            seq: 0,
+            udp: Vec::new(),
+            tcp: Vec::new(),
+        })
+    }
+
+    /// Marking the last UDP probe arrived with and the length of the reply.
+    fn udp_probe(&self, start: Instant) -> Result<(Marking, usize)> {
+        let probe = wire::packet(MARK, &[self.id, self.seq]);
+        self.socket.send(&probe)?;
+        let mut buf = [0u8; 64];
+        // Skip late replies of earlier probes.
+        loop {
+            let remain = self
+                .config
+                .timeout
+                .checked_sub(start.elapsed())
+                .filter(|remain| *remain > Duration::from_secs(0))
+                .ok_or(Error::Timeout)?;
+            self.socket.set_read_timeout(Some(remain))?;
+            let len = self
+                .socket
+                .recv(&mut buf)
+                .context("Failed to receive marking report")?;
+            if len < MARK_LEN || buf[..MARK_LEN] != probe[..] {
+                continue;
+            }
+            return match buf[MARK_LEN..len] {
+                [1, tos] => Ok((Marking::from_tos(tos), len)),
+                [0, _] => Err(Error::Protocol(
+                    "Server doesn't know the marking of received packets".into(),
+                )),
+                _ => Err(Error::UnexpectedReply(format!(
+                    "Invalid marking report of {} bytes",
+                    len
+                ))),
+            };
+        }
+    }
+
+    /// Marking the handshake of a new TCP connection arrived with.
+    fn tcp_probe(&self) -> Result<Marking> {
+        let mut stream = socket::tcp_connect(&self.target, &self.config, &TcpOptions::default())?;
+        stream.set_read_timeout(Some(self.config.timeout))?;
+        stream.write_all(b"MARKING\r\n")?;
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line)?;
+        let _ = stream.write_all(b"QUIT\r\n");
+        line.strip_prefix("MARKING ")
+            .and_then(|tos| tos.trim().parse().ok())
+            .map(Marking::from_tos)
+            .ok_or_else(|| {
+                Error::Protocol(format!("Server can't report TCP marking: {}", line.trim()))
+            })
+    }
+
+    fn verdict(&self, received: Marking, ecn: bool) -> String {
+        let dscp = Change::dscp(self.sent.dscp, received.dscp);
+        if ecn {
+            format!(
+                "DSCP {}, ECN {}",
+                dscp,
+                Change::ecn(self.sent.ecn, received.ecn)
+            )
+        } else {
+            format!("DSCP {}", dscp)
+        }
+    }
+
+    fn log_summary(&self, proto: &str, received: &[Marking], ecn: bool) {
+        let mut counts: Vec<(Marking, usize)> = Vec::new();
+        for &marking in received {
+            match counts.iter_mut().find(|(m, _)| *m == marking) {
+                Some((_, n)) => *n += 1,
+                None => counts.push((marking, 1)),
+            }
+        }
+        for (marking, n) in counts {
+            info!(
+                "{}: {} of {} arrived with {}: {}",
+                proto,
+                n,
+                received.len(),
+                marking,
+                self.verdict(marking, ecn)
+            );
+        }
+    }
+}
+
+impl Task for MarkingTask {
+    fn run(&mut self) -> Result<Measurement> {
+        self.seq += 1;
+        let start = Instant::now();
+        let (received, len) = self.udp_probe(start)?;
+        let time = start.elapsed();
+        info!(
+            "UDP from {}: seq={} time={:?} arrived with {}: {}",
+            self.target,
+            self.seq,
+            time,
+            received,
+            self.verdict(received, true)
+        );
+        self.udp.push(received);
+        // The kernel sets the ECN bits of TCP by itself, only DSCP is compared.
+        match self.tcp_probe() {
+            Ok(received) => {
+                info!(
+                    "TCP to {}: handshake arrived with DSCP {}: {}",
+                    self.target,
+                    received.dscp,
+                    self.verdict(received, false)
+                );
+                self.tcp.push(received);
+            }
+            Err(e) => warn!("TCP marking check failed: {}", e),
+        }
+        Ok(Measurement::Time(Probe::new(
+            self.target,
+            self.seq,
+            len,
+            time,
+        )))
+    }
+
+    fn summary(&self) {
+        info!("Sent {}", self.sent);
+        self.log_summary("UDP", &self.udp, true);
+        self.log_summary("TCP", &self.tcp, false);
+    }
+}
+
+#[test]
+fn changes() {
+    assert_eq!(Marking::from_tos(0xb8).dscp, 46);
+    assert_eq!(Marking::from_tos(0xba).ecn, Ecn::Ect0);
+    assert_eq!(Change::dscp(46, 0), Change::Bleached);
+    assert_eq!(Change::dscp(46, 10), Change::Remarked);
+    assert_eq!(
+        Change::ecn(Ecn::Ect0, Ecn::Ce),
+        Change::CongestionExperienced
+    );
+    assert_eq!(Change::ecn(Ecn::Ect0, Ecn::Ect1), Change::Remarked);
+    assert_eq!(dscp_name(10).as_deref(), Some("AF11"));
+    assert_eq!(dscp_name(38).as_deref(), Some("AF43"));
+}
@@ -0,0 +1,177 @@
+use crate::error::{Context, Result};
+use crate::http;
+use crate::Measurement;
+use log::warn;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Upper bounds of the RTT histogram buckets in seconds.
+const RTT_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// Metrics of one test of one target.
+#[derive(Default)]
+struct Series {
+    sent: u64,
+    lost: u64,
+    /// Probes with an RTT of at most each of `RTT_BUCKETS`, not cumulative.
+    buckets: [u64; RTT_BUCKETS.len()],
+    rtt_count: u64,
+    /// Seconds.
+    rtt_sum: f64,
+    /// Bits per second of the last transfer.
+    bandwidth: Option<f64>,
+}
+
+/// Results of tasks in the Prometheus text format, labelled by test and target.
+/// Clones share the same metrics.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    series: Arc<Mutex<BTreeMap<(String, String), Series>>>,
+}
+
+/// Escape a label value of the text format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Count a run of `test` to `target`, `None` if it failed.
+    pub fn record(&self, test: &str, target: &str, result: Option<&Measurement>) {
+        let mut series = self.series.lock().unwrap();
+        let series = series
+            .entry((test.to_string(), target.to_string()))
+            .or_default();
+        series.sent += 1;
+        match result {
+            None => series.lost += 1,
+            Some(Measurement::Time(probe)) => {
+                let rtt = probe.time.as_secs_f64();
+                if let Some(i) = RTT_BUCKETS.iter().position(|&le| rtt <= le) {
+                    series.buckets[i] += 1;
+                }
+                series.rtt_count += 1;
+                series.rtt_sum += rtt;
+            }
+            Some(Measurement::Speed(transfer)) => {
+                series.bandwidth = Some(transfer.mbps() * 1_000_000.0);
+            }
+        }
+    }
+
+    /// All metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let series = self.series.lock().unwrap();
+        let mut out = String::new();
+        let labels = |test: &str, target: &str| {
+            format!("test=\"{}\",target=\"{}\"", escape(test), escape(target))
+        };
+        let mut counter = |name: &str, help: &str, value: &dyn Fn(&Series) -> u64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for ((test, target), s) in series.iter() {
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels(test, target), value(s));
+            }
+        };
+        counter(
+            "nettest_probes_sent_total",
+            "Runs of the test.",
+            &|s: &Series| s.sent,
+        );
+        counter(
+            "nettest_probes_lost_total",
+            "Runs that failed.",
+            &|s: &Series| s.lost,
+        );
+        out.push_str("# HELP nettest_rtt_seconds Round-trip time of latency probes.\n");
+        out.push_str("# TYPE nettest_rtt_seconds histogram\n");
+        for ((test, target), s) in series.iter().filter(|(_, s)| s.rtt_count > 0) {
+            let labels = labels(test, target);
+            let mut count = 0;
+            for (le, n) in RTT_BUCKETS.iter().zip(&s.buckets) {
+                count += n;
+                let _ = writeln!(
+                    out,
+                    "nettest_rtt_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, le, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "nettest_rtt_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, s.rtt_count
+            );
+            let _ = writeln!(out, "nettest_rtt_seconds_sum{{{}}} {}", labels, s.rtt_sum);
+            let _ = writeln!(
+                out,
+                "nettest_rtt_seconds_count{{{}}} {}",
+                labels, s.rtt_count
+            );
+        }
+        out.push_str("# HELP nettest_bandwidth_bits_per_second Throughput of the last transfer.\n");
+        out.push_str("# TYPE nettest_bandwidth_bits_per_second gauge\n");
+        for ((test, target), s) in series.iter() {
+            if let Some(bandwidth) = s.bandwidth {
+                let _ = writeln!(
+                    out,
+                    "nettest_bandwidth_bits_per_second{{{}}} {}",
+                    labels(test, target),
+                    bandwidth
+                );
+            }
+        }
+        out
+    }
+
+    /// Serve `render` at `http://<addr>/metrics` on a new thread.
+    pub fn serve(&self, addr: SocketAddr) -> Result<()> {
+        let listener =
+            TcpListener::bind(addr).with_context(|| format!("Failed to listen on {}", addr))?;
+        let metrics = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| http::respond(stream, || metrics.render()));
+                if let Err(e) = result {
+                    warn!("Metrics request error: {}", e);
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+#[test]
+fn render() {
+    use crate::{Probe, Transfer};
+    use std::time::Duration;
+    let metrics = Metrics::new();
+    let target: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+    let probe = Measurement::Time(Probe::new(target, 1, 64, Duration::from_millis(20)));
+    metrics.record("ping", "example.com", Some(&probe));
+    metrics.record("ping", "example.com", None);
+    let transfer = Measurement::Speed(Transfer::new(target, 1_000_000, Duration::from_secs(1)));
+    metrics.record("tcpdownload", "example.com", Some(&transfer));
+    let text = metrics.render();
+    assert!(text.contains("nettest_probes_sent_total{test=\"ping\",target=\"example.com\"} 2"));
+    assert!(text.contains("nettest_probes_lost_total{test=\"ping\",target=\"example.com\"} 1"));
+    assert!(text.contains(
+        "nettest_rtt_seconds_bucket{test=\"ping\",target=\"example.com\",le=\"0.01\"} 0"
+    ));
+    assert!(text.contains(
+        "nettest_rtt_seconds_bucket{test=\"ping\",target=\"example.com\",le=\"0.025\"} 1"
+    ));
+    assert!(text.contains(
+        "nettest_bandwidth_bits_per_second{test=\"tcpdownload\",target=\"example.com\"} 8000000"
+    ));
+}
@@ -0,0 +1,425 @@
+use crate::asynctask::{AsyncTask, AsyncTcpingTask, AsyncUdpingTask, Threaded};
+use crate::config::TestConfig;
+use crate::error::{Context, Error, Result};
+use crate::metrics::Metrics;
+use crate::quic::QuicOptions;
+use crate::socket::TcpOptions;
+use crate::stats::Stats;
+use crate::{Measurement, PingTask, QuicpingTask, TcpdownloadTask, TcpuploadTask};
+use log::{info, warn};
+use rand::{Rng, SeedableRng};
+use rand_xoshiro::Xoshiro256Plus;
+use std::collections::VecDeque;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Tests a monitor can run.
+const TESTS: &[&str] = &[
+    "ping",
+    "tcping",
+    "udping",
+    "quicping",
+    "tcpdownload",
+    "tcpupload",
+];
+
+/// Limit on a statistic of the rolling window of a target.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Threshold {
+    /// Percent of failed runs.
+    MaxLoss(f64),
+    /// Average round-trip time in milliseconds.
+    MaxRtt(f64),
+    /// Average throughput in Mbps.
+    MinSpeed(f64),
+}
+
+impl Threshold {
+    /// Value of the statistic and whether it's over the limit, `None` if `stats` doesn't have it.
+    pub fn check(self, stats: &Stats) -> Option<(f64, bool)> {
+        match self {
+            Threshold::MaxLoss(max) => Some((stats.loss(), stats.loss() > max)),
+            Threshold::MaxRtt(max) => stats.latency.map(|l| {
+                let rtt = l.avg.as_micros() as f64 / 1000.0;
+                (rtt, rtt > max)
+            }),
+            Threshold::MinSpeed(min) => stats.speed.map(|s| (s.avg, s.avg < min)),
+        }
+    }
+
+    fn limit(self) -> f64 {
+        match self {
+            Threshold::MaxLoss(v) | Threshold::MaxRtt(v) | Threshold::MinSpeed(v) => v,
+        }
+    }
+
+    /// Name and unit of the statistic.
+    fn metric(self) -> (&'static str, &'static str) {
+        match self {
+            Threshold::MaxLoss(_) => ("loss", "%"),
+            Threshold::MaxRtt(_) => ("rtt", " ms"),
+            Threshold::MinSpeed(_) => ("speed", " Mbps"),
+        }
+    }
+}
+
+/// One test of a monitor, a line of its config file:
+/// `<test> <address> [interval=60] [window=20] [size=..] [timeout=..] [max-loss=..] [max-rtt=..] [min-speed=..]`
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub test: String,
+    pub address: String,
+    /// Time between runs, before jitter.
+    pub interval: Duration,
+    /// Number of last runs statistics are computed of.
+    pub window: usize,
+    pub size: Option<usize>,
+    pub timeout: Option<Duration>,
+    pub thresholds: Vec<Threshold>,
+}
+
+impl Target {
+    fn parse(line: &str) -> Result<Target> {
+        let mut words = line.split_whitespace();
+        let (test, address) = match (words.next(), words.next()) {
+            (Some(test), Some(address)) => (test, address),
+            _ => return Err(Error::InvalidInput("Expect a test and an address".into())),
+        };
+        if !TESTS.contains(&test) {
+            return Err(Error::InvalidInput(format!(
+                "Unknown test {}, expect one of {}",
+                test,
+                TESTS.join(", ")
+            )));
+        }
+        let mut target = Target {
+            test: test.to_string(),
+            address: address.to_string(),
+            interval: Duration::from_secs(60),
+            window: 20,
+            size: None,
+            timeout: None,
+            thresholds: Vec::new(),
+        };
+        for option in words {
+            let mut kv = option.splitn(2, '=');
+            let (key, value) = match (kv.next(), kv.next()) {
+                (Some(key), Some(value)) => (key, value),
+                _ => return Err(Error::InvalidInput(format!("Expect key=value: {}", option))),
+            };
+            let invalid = || Error::InvalidInput(format!("Invalid {}: {}", key, value));
+            let number = || {
+                value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|v| *v >= 0.0)
+                    .ok_or_else(invalid)
+            };
+            // Duration::from_secs_f64 panics on infinite and too large values.
+            let secs = || {
+                number()
+                    .ok()
+                    .filter(|v| v.is_finite() && *v < u64::MAX as f64)
+                    .map(Duration::from_secs_f64)
+                    .ok_or_else(invalid)
+            };
+            match key {
+                "interval" => target.interval = secs()?,
+                "window" => {
+                    target.window = value.parse().ok().filter(|&w| w > 0).ok_or_else(invalid)?
+                }
+                "size" => target.size = Some(value.parse().map_err(|_| invalid())?),
+                "timeout" => target.timeout = Some(secs()?),
+                "max-loss" => target.thresholds.push(Threshold::MaxLoss(number()?)),
+                "max-rtt" => target.thresholds.push(Threshold::MaxRtt(number()?)),
+                "min-speed" => target.thresholds.push(Threshold::MinSpeed(number()?)),
+                _ => return Err(Error::InvalidInput(format!("Unknown option {}", key))),
+            }
+        }
+        Ok(target)
+    }
+
+    async fn task(&self, config: &TestConfig) -> Result<Box<dyn AsyncTask>> {
+        let mut config = config.clone();
+        if let Some(size) = self.size {
+            config.size = size;
+        }
+        if let Some(timeout) = self.timeout {
+            config.timeout = timeout;
+        }
+        let address = self.address.clone();
+        let tcp = TcpOptions::default();
+        Ok(match self.test.as_str() {
+            "tcping" => Box::new(AsyncTcpingTask::new(&address, &config, &tcp).await?),
+            "udping" => Box::new(AsyncUdpingTask::new(&address, &config).await?),
+            // The others resolve and connect blocking, on the thread pool for blocking work.
+            "ping" => Box::new(Threaded::new(
+                async_std::task::spawn_blocking(move || PingTask::new(&address, &config)).await?,
+            )),
+            "quicping" => Box::new(Threaded::new(
+                async_std::task::spawn_blocking(move || {
+                    QuicpingTask::new(&address, &config, &QuicOptions::default())
+                })
+                .await?,
+            )),
+            "tcpdownload" => Box::new(Threaded::new(
+                async_std::task::spawn_blocking(move || {
+                    TcpdownloadTask::new(&address, &config, &tcp)
+                })
+                .await?,
+            )),
+            "tcpupload" => Box::new(Threaded::new(
+                async_std::task::spawn_blocking(move || {
+                    TcpuploadTask::new(&address, &config, &tcp, false)
+                })
+                .await?,
+            )),
+            _ => unreachable!("Test is checked by parse"),
+        })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.test, self.address)
+    }
+}
+
+/// Targets of a monitor config file. Empty lines and `#` comments are skipped.
+fn load(path: &Path) -> Result<Vec<Target>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut targets = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.splitn(2, '#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        targets.push(Target::parse(line).with_context(|| format!("{}:{}", path.display(), i + 1))?);
+    }
+    if targets.is_empty() {
+        return Err(Error::InvalidInput(format!(
+            "No target in {}",
+            path.display()
+        )));
+    }
+    Ok(targets)
+}
+
+/// A threshold of a target crossed, or back within the limit.
+#[derive(Clone, Debug)]
+pub struct Alert {
+    pub target: String,
+    pub threshold: Threshold,
+    pub value: f64,
+    /// Crossed now, otherwise recovered.
+    pub raised: bool,
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (name, unit) = self.threshold.metric();
+        let limit = self.threshold.limit();
+        let side = match self.threshold {
+            Threshold::MinSpeed(_) => "below",
+            _ => "above",
+        };
+        if self.raised {
+            write!(
+                f,
+                "{}: {} {:.2}{} is {} {}{}",
+                self.target, name, self.value, unit, side, limit, unit
+            )
+        } else {
+            write!(
+                f,
+                "{}: {} {:.2}{} is back within {}{}",
+                self.target, name, self.value, unit, limit, unit
+            )
+        }
+    }
+}
+
+/// Results of the last runs of a target.
+struct Window {
+    results: VecDeque<Option<Measurement>>,
+    len: usize,
+}
+
+impl Window {
+    fn new(len: usize) -> Window {
+        Window {
+            results: VecDeque::with_capacity(len),
+            len,
+        }
+    }
+
+    fn push(&mut self, result: Option<Measurement>) {
+        if self.results.len() == self.len {
+            self.results.pop_front();
+        }
+        self.results.push_back(result);
+    }
+
+    fn stats(&self) -> Stats {
+        let failed = self.results.iter().filter(|r| r.is_none()).count();
+        Stats::new(self.results.iter().flatten(), failed)
+    }
+}
+
+/// Runs the tests of a config file forever, the targets concurrently on one
+/// async-std executor with a timer each.
+pub struct Monitor {
+    targets: Vec<Target>,
+    config: TestConfig,
+    jitter: f64,
+    metrics: Option<Metrics>,
+}
+
+impl Monitor {
+    pub fn new(targets: Vec<Target>, config: &TestConfig) -> Monitor {
+        Monitor {
+            targets,
+            config: config.clone(),
+            jitter: 0.1,
+            metrics: None,
+        }
+    }
+
+    /// Monitor of the targets in the config file at `path`, see `Target`.
+    pub fn load(path: &Path, config: &TestConfig) -> Result<Monitor> {
+        Ok(Monitor::new(load(path)?, config))
+    }
+
+    /// Shift every run randomly by up to this fraction of its interval, so
+    /// tests of many monitors don't hit a server at the same time.
+    pub fn jitter(mut self, jitter: f64) -> Monitor {
+        self.jitter = jitter.max(0.0).min(1.0);
+        self
+    }
+
+    /// Record the result of every run in `metrics`.
+    pub fn metrics(mut self, metrics: &Metrics) -> Monitor {
+        self.metrics = Some(metrics.clone());
+        self
+    }
+
+    /// Never returns, `alert` is called when a threshold is crossed or recovers.
+    pub fn run<F: Fn(&Alert) + Send + Sync + 'static>(self, alert: F) {
+        let Monitor {
+            targets,
+            config,
+            jitter,
+            metrics,
+        } = self;
+        let alert = Arc::new(alert);
+        let monitors: Vec<_> = targets
+            .into_iter()
+            .map(|target| {
+                let config = config.clone();
+                let alert = Arc::clone(&alert);
+                let metrics = metrics.clone();
+                async_std::task::spawn(async move {
+                    monitor(&target, &config, jitter, metrics, &*alert).await
+                })
+            })
+            .collect();
+        async_std::task::block_on(async {
+            for monitor in monitors {
+                monitor.await;
+            }
+        });
+    }
+}
+
+async fn monitor(
+    target: &Target,
+    config: &TestConfig,
+    jitter: f64,
+    metrics: Option<Metrics>,
+    alert: &(dyn Fn(&Alert) + Send + Sync),
+) {
+    let mut rng = Xoshiro256Plus::from_entropy();
+    // Random factor between -jitter and jitter.
+    let mut shift = || {
+        if jitter > 0.0 {
+            rng.gen_range(-jitter, jitter)
+        } else {
+            0.0
+        }
+    };
+    let mut task: Option<Box<dyn AsyncTask>> = None;
+    let mut window = Window::new(target.window);
+    let mut raised = vec![false; target.thresholds.len()];
+    let mut runs = 0;
+    // Spread the first runs too.
+    async_std::task::sleep(target.interval.mul_f64(shift().abs())).await;
+    loop {
+        let start = Instant::now();
+        if task.is_none() {
+            // Retried every interval, e.g. while DNS is down.
+            match target.task(config).await {
+                Ok(t) => task = Some(t),
+                Err(e) => warn!("{}: {}", target, e),
+            }
+        }
+        let result = match &mut task {
+            Some(task) => match task.run().await {
+                Ok(measurement) => Some(measurement),
+                Err(e) => {
+                    info!("{}: {}", target, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        if let Some(metrics) = &metrics {
+            metrics.record(&target.test, &target.address, result.as_ref());
+        }
+        window.push(result);
+        runs += 1;
+        let stats = window.stats();
+        if runs % target.window == 0 {
+            info!("--- {}: last {} runs ---", target, target.window);
+            stats.log();
+        }
+        for (threshold, raised) in target.thresholds.iter().zip(&mut raised) {
+            if let Some((value, over)) = threshold.check(&stats) {
+                if over != *raised {
+                    *raised = over;
+                    alert(&Alert {
+                        target: target.to_string(),
+                        threshold: *threshold,
+                        value,
+                        raised: over,
+                    });
+                }
+            }
+        }
+        let interval = target.interval.mul_f64(1.0 + shift());
+        if let Some(wait) = interval.checked_sub(start.elapsed()) {
+            async_std::task::sleep(wait).await;
+        }
+    }
+}
+
+#[test]
+fn parse_target() {
+    let target =
+        Target::parse("tcping example.com:443 interval=30 max-loss=5 max-rtt=100").unwrap();
+    assert_eq!(target.test, "tcping");
+    assert_eq!(target.address, "example.com:443");
+    assert_eq!(target.interval, Duration::from_secs(30));
+    assert_eq!(target.window, 20);
+    assert_eq!(
+        target.thresholds,
+        vec![Threshold::MaxLoss(5.0), Threshold::MaxRtt(100.0)]
+    );
+    assert!(Target::parse("ping").is_err());
+    assert!(Target::parse("traceroute 1.1.1.1").is_err());
+    assert!(Target::parse("ping 1.1.1.1 window=0").is_err());
+    assert!(Target::parse("ping 1.1.1.1 interval=inf").is_err());
+    assert!(Target::parse("ping 1.1.1.1 timeout=1e30").is_err());
+}
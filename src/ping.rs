@@ -1,14 +1,14 @@
-use crate::{Measurement, Task};
-use anyhow::{anyhow, Context, Result};
+use crate::config::{Pattern, TestConfig};
+use crate::error::{Context, Error, Result};
+use crate::socket;
+use crate::{Measurement, Probe, Task};
 use log::info;
-use rand::{Rng, SeedableRng};
-use rand_xoshiro::Xoshiro256Plus;
 use socket2::{Domain, Protocol, Socket, Type};
 use std::convert::{TryFrom, TryInto};
 use std::io::ErrorKind;
 use std::mem::size_of;
-use std::net::SocketAddr;
-use std::time::{Duration, Instant, SystemTime};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Instant, SystemTime};
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 #[repr(u8)]
@@ -20,20 +20,23 @@ enum IcmpType {
 }
 
 impl TryFrom<u8> for IcmpType {
-    type Error = anyhow::Error;
+    type Error = Error;
     fn try_from(buf: u8) -> Result<Self> {
         match buf {
             0 => Ok(Self::EchoReply),
             8 => Ok(Self::EchoRequest),
             128 => Ok(Self::EchoRequestV6),
             129 => Ok(Self::EchoReplyV6),
-            _ => Err(anyhow!("Not related to ICMP Ping: {:?}", { buf })),
+            _ => Err(Error::UnexpectedReply(format!(
+                "Not related to ICMP Ping: {:?}",
+                { buf }
+            ))),
         }
     }
 }
 
 #[derive(Copy, Clone, Debug)]
-struct Icmphdr {
+pub(crate) struct Icmphdr {
     icmp_type: IcmpType,
     /// constant: 0
     code: u8,
@@ -41,20 +44,20 @@ struct Icmphdr {
     checksum: u16,
     /// input by kernel
     id: u16,
-    seq: u16,
+    pub(crate) seq: u16,
     /// This is not a part of ICMP header
     time_stamp: u64,
 }
 
 impl TryFrom<&[u8]> for Icmphdr {
-    type Error = anyhow::Error;
+    type Error = Error;
     fn try_from(buf: &[u8]) -> Result<Self> {
         if buf.len() < size_of::<Self>() {
-            Err(anyhow!(
+            Err(Error::UnexpectedReply(format!(
                 "Buffer too short: expect {} bytes, actual: {} bytes",
                 size_of::<Self>(),
                 buf.len()
-            ))
+            )))
         } else {
             Ok(Icmphdr {
                 icmp_type: buf[0].try_into()?,
@@ -69,7 +72,7 @@ impl TryFrom<&[u8]> for Icmphdr {
 }
 
 impl Icmphdr {
-    fn echo(seq: u16, v6: bool) -> Self {
+    pub(crate) fn echo(seq: u16, v6: bool) -> Self {
         Icmphdr {
             icmp_type: if v6 {
                 IcmpType::EchoRequestV6
@@ -86,14 +89,14 @@ impl Icmphdr {
                 .as_secs(),
         }
     }
-    fn bump(&mut self) {
+    pub(crate) fn bump(&mut self) {
         self.seq = self.seq.overflowing_add(1).0;
         self.time_stamp = SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_secs();
     }
-    fn vec(&self) -> Vec<u8> {
+    pub(crate) fn vec(&self) -> Vec<u8> {
         let mut buf = Vec::with_capacity(size_of::<Self>());
         buf.push(self.icmp_type as u8);
         buf.push(self.code);
@@ -103,6 +106,53 @@ impl Icmphdr {
         buf.extend_from_slice(&self.time_stamp.to_le_bytes());
         buf
     }
+
+    /// Header of an echo reply to `target` received from `from`.
+    pub(crate) fn reply(buf: &[u8], from: IpAddr, target: IpAddr) -> Result<Icmphdr> {
+        let hdr: Icmphdr = buf.try_into().context("Packet is broken")?;
+        if from.is_ipv4() && hdr.icmp_type != IcmpType::EchoReply
+            || from.is_ipv6() && hdr.icmp_type != IcmpType::EchoReplyV6
+        {
+            return Err(Error::UnexpectedReply(
+                "Received packet isn't echo reply".into(),
+            ));
+        }
+        if from != target {
+            return Err(Error::UnexpectedReply(
+                "Received packet isn't sent from target".into(),
+            ));
+        }
+        Ok(hdr)
+    }
+}
+
+/// ICMP socket pinging `target`, prepared as `config` says.
+pub(crate) fn icmp_socket(target: &SocketAddr, config: &TestConfig) -> Result<Socket> {
+    if !config.resolve_family().matches(target) {
+        return Err(Error::InvalidInput(format!(
+            "{} isn't in address family {:?}",
+            target.ip(),
+            config.resolve_family()
+        )));
+    }
+    let ip = target.ip();
+    info!("PING ({}) {} bytes of data.", ip, config.size);
+    let socket = if ip.is_ipv4() {
+        Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::ICMPV4))
+    } else if ip.is_ipv6() {
+        Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::ICMPV6))
+    } else {
+        unreachable!("IP is either ipv4 or ipv6")
+    };
+    let socket = match socket{
+        Ok(s) => s,
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            return Err(Error::PermissionDenied(format!("Failed to create ICMP socket: {}, you need to run `sudo sysctl -w net.ipv4.ping_group_range='0 1000'`",e)))
+        },
+        Err(e) => return Err(Error::Io("Failed to create ICMP socket".into(), e)),
+    };
+    socket::prepare(&socket, target, config)?;
+    Ok(socket)
 }
 
 pub struct PingTask {
@@ -110,99 +160,75 @@ pub struct PingTask {
     target: SocketAddr,
     echo_hdr: Icmphdr,
     size: usize,
+    pattern: Pattern,
 }
 
 impl PingTask {
-    pub fn new(addr: &str, size: usize, timeout: u64) -> Result<PingTask> {
-        let ip = addr.parse().unwrap_or({
-            use std::net::ToSocketAddrs;
-            let socket = &format!("{}:0", addr);
-            socket
-                .to_socket_addrs()
-                .context("Can't resolve IP address")?
-                .next()
-                .ok_or(anyhow!("Don't have IP address"))?
-                .ip()
-        });
-        info!("PING ({}) {} bytes of data.", ip, size);
-        let socket = if ip.is_ipv4() {
-            Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::ICMPV4))
-        } else if ip.is_ipv6() {
-            Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::ICMPV6))
-        } else {
-            unreachable!("IP is either ipv4 or ipv6")
-        };
-        let socket = match socket{
-            Ok(s) => s,
-            Err(e) if e.kind() == ErrorKind::PermissionDenied => {
-                return Err(anyhow!("Failed to create ICMP socket: {}, you need to run `sudo sysctl -w net.ipv4.ping_group_range='0 1000'`",e))
-            },
-            Err(e) => return Err(anyhow!("Failed to create ICMP socket: {}",e)),
+    pub fn new(addr: &str, config: &TestConfig) -> Result<PingTask> {
+        let target = match addr.parse() {
+            Ok(ip) => SocketAddr::new(ip, 0),
+            Err(_) => socket::resolve(&format!("{}:0", addr), config)?,
         };
-        socket.set_read_timeout(Some(Duration::from_secs(timeout)))?;
+        let socket = icmp_socket(&target, config)?;
+        socket.set_read_timeout(Some(config.timeout))?;
         Ok(PingTask {
             socket,
-            target: SocketAddr::new(ip, 0),
-            echo_hdr: Icmphdr::echo(0, ip.is_ipv6()),
-            size: size.max(size_of::<Icmphdr>()),
+            target,
+            echo_hdr: Icmphdr::echo(0, target.is_ipv6()),
+            size: config.size.max(size_of::<Icmphdr>()),
+            pattern: config.pattern,
         })
     }
 }
 
 impl Task for PingTask {
     fn run(&mut self) -> Result<Measurement> {
-        let rand_str = Xoshiro256Plus::from_entropy()
-            .sample_iter(&rand::distributions::Alphanumeric)
-            .map(|x| x as u8)
-            .take(self.size - size_of::<Icmphdr>());
         self.echo_hdr.bump();
         let mut buffer = self.echo_hdr.vec();
-        buffer.extend(rand_str);
+        buffer.resize(self.size, 0);
+        self.pattern.fill(&mut buffer[size_of::<Icmphdr>()..]);
         let send_time = Instant::now();
         let size = self
             .socket
             .send_to(&buffer, &self.target.into())
             .context("Failed to send echo request")?;
         if size != buffer.len() {
-            return Err(anyhow!(
+            return Err(Error::Protocol(format!(
                 "Failed to send echo request({} bytes), only {} bytes sent",
                 buffer.len(),
                 size
-            ));
+            )));
         }
         let (size, addr) = self
             .socket
             .recv_from(&mut buffer)
-            .map_err(|e| match e.kind() {
-                ErrorKind::WouldBlock | ErrorKind::TimedOut => anyhow!("Timed out"),
-                _ => anyhow!("Failed to receive echo reply: {:?}", e),
-            })?;
+            .context("Failed to receive echo reply")?;
         let time = send_time.elapsed();
-        let recv_hdr: Icmphdr = buffer.as_slice().try_into().context("Packet is broken")?;
         let ip = addr.as_std().unwrap().ip();
-        if ip.is_ipv4() && recv_hdr.icmp_type != IcmpType::EchoReply
-            || ip.is_ipv6() && recv_hdr.icmp_type != IcmpType::EchoReplyV6
-        {
-            return Err(anyhow!("Received packet isn't echo reply"));
-        }
-        if ip != self.target.ip() {
-            return Err(anyhow!("Received packet isn't sent from target"));
-        }
+        let recv_hdr = Icmphdr::reply(&buffer, ip, self.target.ip())?;
         info!(
             "{} bytes from {}: icmp_seq={} time={:?}",
             size, ip, recv_hdr.seq, time
         );
-        Ok(Measurement::Time(time))
+        Ok(Measurement::Time(Probe::new(
+            self.target,
+            recv_hdr.seq as u64,
+            size,
+            time,
+        )))
     }
 }
 
 #[test]
 fn ping_localhost() {
-    let mut ping = PingTask::new("127.0.0.1".into(), 64, 1).unwrap();
+    let config = TestConfig::new()
+        .size(64)
+        .timeout(std::time::Duration::from_secs(1));
+    let mut ping = PingTask::new("127.0.0.1", &config).unwrap();
     let _ = ping.run().unwrap();
 }
 
 #[test]
 fn dont_resolve() {
-    assert!(PingTask::new("thissitedontexist.abc".into(), 64, 1).is_err())
+    assert!(PingTask::new("thissitedontexist.abc", &TestConfig::new()).is_err())
 }
@@ -0,0 +1,363 @@
+use crate::config::{Pattern, TestConfig};
+use crate::error::{Error, Result};
+use crate::socket;
+use crate::stats::percentile;
+use crate::wire::{self, ECHO, ECHO_LEN};
+use crate::{Measurement, Probe, Task};
+use log::info;
+use rand::{Rng, SeedableRng};
+use rand_xoshiro::Xoshiro256Plus;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Time to wait for the echoes of the last packets.
+const DRAIN: Duration = Duration::from_secs(1);
+
+/// Application traffic pattern replayed by `ProfileTask`.
+#[derive(Clone, Copy, Debug)]
+pub enum Profile {
+    /// G.711 at 20 ms packetisation, 160 bytes of audio plus RTP header.
+    VoipG711,
+    /// Opus at 24 kbit/s and 20 ms packetisation.
+    VoipOpus,
+    /// 4 Mbit/s video at 30 frames per second, every frame sent as a burst.
+    Video,
+    /// Small game state updates at 64 ticks per second.
+    Game,
+}
+
+impl std::str::FromStr for Profile {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Profile> {
+        match s {
+            "voip-g711" => Ok(Profile::VoipG711),
+            "voip-opus" => Ok(Profile::VoipOpus),
+            "video" => Ok(Profile::Video),
+            "game" => Ok(Profile::Game),
+            _ => Err(Error::InvalidInput(format!("Unknown profile {}", s))),
+        }
+    }
+}
+
+/// Codec parameters of the E-model (ITU-T G.113 Appendix I).
+struct Codec {
+    /// Equipment impairment factor.
+    ie: f64,
+    /// Packet-loss robustness factor.
+    bpl: f64,
+}
+
+impl Profile {
+    /// Time between ticks.
+    fn interval(self) -> Duration {
+        match self {
+            Profile::VoipG711 | Profile::VoipOpus => Duration::from_millis(20),
+            Profile::Video => Duration::from_micros(33_333),
+            Profile::Game => Duration::from_micros(15_625),
+        }
+    }
+
+    /// Sizes of the UDP payloads sent every tick.
+    fn burst(self, rng: &mut Xoshiro256Plus) -> Vec<usize> {
+        match self {
+            Profile::VoipG711 => vec![172],
+            Profile::VoipOpus => vec![72],
+            Profile::Video => {
+                // 4 Mbit/s on average, frame sizes vary by a quarter.
+                let frame = 4_000_000 / 8 / 30 * rng.gen_range(75, 125) / 100;
+                let mut sizes = vec![1200; frame / 1200];
+                if frame % 1200 >= ECHO_LEN {
+                    sizes.push(frame % 1200);
+                }
+                sizes
+            }
+            Profile::Game => vec![rng.gen_range(64, 160)],
+        }
+    }
+
+    fn codec(self) -> Option<Codec> {
+        match self {
+            // G.711 with packet loss concealment.
+            Profile::VoipG711 => Some(Codec { ie: 0.0, bpl: 25.1 }),
+            // Opus isn't in G.113, these are commonly used estimates for wideband speech
+            // mapped to the narrowband scale.
+            Profile::VoipOpus => Some(Codec {
+                ie: 10.0,
+                bpl: 20.0,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// R-factor of the simplified E-model, `delay` is one-way mouth-to-ear, `loss` in percent.
+fn r_factor(codec: &Codec, delay: Duration, loss: f64) -> f64 {
+    let ta = delay.as_micros() as f64 / 1000.0;
+    let id = 0.024 * ta + if ta > 177.3 { 0.11 * (ta - 177.3) } else { 0.0 };
+    let ie_eff = codec.ie + (95.0 - codec.ie) * loss / (loss + codec.bpl);
+    93.2 - id - ie_eff
+}
+
+/// Mean opinion score of an R-factor (ITU-T G.107).
+fn mos(r: f64) -> f64 {
+    if r <= 0.0 {
+        1.0
+    } else if r >= 100.0 {
+        4.5
+    } else {
+        1.0 + 0.035 * r + r * (r - 60.0) * (100.0 - r) * 7e-6
+    }
+}
+
+/// Replay an application traffic pattern to the nettest server's UDP reflector.
+pub struct ProfileTask {
+    target: SocketAddr,
+    socket: UdpSocket,
+    profile: Profile,
+    duration: Duration,
+    pattern: Pattern,
+    rng: Xoshiro256Plus,
+    runs: u64,
+    /// Round-trip times and lost packets of all runs.
+    rtts: Vec<Duration>,
+    lost: usize,
+    /// Latency of complete frames and frames missing a packet of all runs.
+    frames: Vec<Duration>,
+    frames_lost: usize,
+    jitter: Duration,
+}
+
+impl ProfileTask {
+    pub fn new(
+        addr: &str,
+        config: &TestConfig,
+        profile: Profile,
+        duration: Duration,
+    ) -> Result<ProfileTask> {
+        let target = socket::resolve(addr, config)?;
+        let socket = socket::udp_connect(&target, config)?;
+        info!(
+            "Replaying {:?} traffic to {} for {:?}",
+            profile, target, duration
+        );
+        Ok(Self {
+            target,
+            socket,
+            profile,
+            duration,
+            pattern: config.pattern,
+            rng: Xoshiro256Plus::from_entropy(),
+            runs: 0,
+            rtts: Vec::new(),
+            lost: 0,
+            frames: Vec::new(),
+            frames_lost: 0,
+            jitter: Duration::default(),
+        })
+    }
+
+    /// Record echoes until nothing is left to read.
+    fn receive(
+        &self,
+        id: u64,
+        start: Instant,
+        buf: &mut [u8],
+        rtts: &mut [Option<Duration>],
+    ) -> Result<()> {
+        loop {
+            let len = match self.socket.recv(buf) {
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    return Ok(())
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let pkt = &buf[..len];
+            if len < ECHO_LEN || wire::kind(pkt) != Some(ECHO) {
+                continue;
+            }
+            let v = |i: usize| wire::u64_at(pkt, wire::field(i)).unwrap();
+            if v(0) != id {
+                continue;
+            }
+            // A send time in the future makes a corrupt or spoofed echo, skip it too.
+            let rtt = start.elapsed().checked_sub(Duration::from_micros(v(3)));
+            if let (Some(slot @ None), Some(rtt)) = (rtts.get_mut(v(1) as usize), rtt) {
+                *slot = Some(rtt);
+            }
+        }
+    }
+
+    fn log_stats(
+        &self,
+        name: &str,
+        rtts: &mut [Duration],
+        lost: usize,
+        jitter: Duration,
+        frames: &mut [Duration],
+        frames_lost: usize,
+    ) {
+        let ms = |t: Duration| t.as_micros() as f64 / 1000.0;
+        rtts.sort();
+        let sent = rtts.len() + lost;
+        let loss = 100.0 * lost as f64 / sent.max(1) as f64;
+        info!(
+            "{}: {} packets, {:.2}% lost, rtt p50/p90/p99 = {:.3}/{:.3}/{:.3} ms, jitter {:.3} ms",
+            name,
+            sent,
+            loss,
+            ms(percentile(rtts, 50.0)),
+            ms(percentile(rtts, 90.0)),
+            ms(percentile(rtts, 99.0)),
+            ms(jitter)
+        );
+        if let Some(codec) = self.profile.codec() {
+            // One-way delay, plus the packetisation and a jitter buffer twice the jitter.
+            let mean = rtts.iter().sum::<Duration>() / rtts.len().max(1) as u32;
+            let delay = mean / 2 + self.profile.interval() + jitter * 2;
+            let r = r_factor(&codec, delay, loss);
+            info!("{}: R-factor {:.1}, MOS {:.2}", name, r, mos(r));
+        }
+        if let Profile::Video = self.profile {
+            frames.sort();
+            info!(
+                "{}: {} frames, {} incomplete, frame latency p50/p90/p99 = {:.3}/{:.3}/{:.3} ms",
+                name,
+                frames.len() + frames_lost,
+                frames_lost,
+                ms(percentile(frames, 50.0)),
+                ms(percentile(frames, 90.0)),
+                ms(percentile(frames, 99.0))
+            );
+        }
+    }
+}
+
+impl Task for ProfileTask {
+    fn run(&mut self) -> Result<Measurement> {
+        let id: u64 = self.rng.gen();
+        let mut buf = vec![0u8; 1500];
+        // Sequence numbers and send time of every frame, that is every tick.
+        let mut frames: Vec<std::ops::Range<usize>> = Vec::new();
+        let mut frame_sent = Vec::new();
+        let mut rtts: Vec<Option<Duration>> = Vec::new();
+        let mut sent = Vec::new();
+        let mut packet = wire::packet(ECHO, &[id, 0, 0, 0]);
+        packet.resize(1500, 0);
+        self.pattern.fill(&mut packet[ECHO_LEN..]);
+        let mut bytes = 0;
+        self.socket.set_nonblocking(true)?;
+        let started = SystemTime::now();
+        let start = Instant::now();
+        let interval = self.profile.interval();
+        while start.elapsed() < self.duration {
+            let due = interval * frames.len() as u32;
+            if let Some(wait) = due.checked_sub(start.elapsed()) {
+                thread::sleep(wait);
+            }
+            let first = rtts.len();
+            frame_sent.push(start.elapsed());
+            for size in self.profile.burst(&mut self.rng) {
+                let seq = rtts.len() as u64;
+                wire::set_u64(&mut packet, wire::field(1), seq);
+                wire::set_u64(&mut packet, wire::field(2), frames.len() as u64);
+                let now = start.elapsed();
+                wire::set_u64(&mut packet, wire::field(3), now.as_micros() as u64);
+                // A full socket buffer drops the packet, as a real-time sender would.
+                match self.socket.send(&packet[..size]) {
+                    Ok(_) => bytes += size,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e.into()),
+                }
+                rtts.push(None);
+                sent.push(now);
+            }
+            frames.push(first..rtts.len());
+            self.receive(id, start, &mut buf, &mut rtts)?;
+        }
+        self.socket.set_nonblocking(false)?;
+        let timeout = self.socket.read_timeout()?;
+        self.socket.set_read_timeout(Some(DRAIN))?;
+        let drained = self.receive(id, start, &mut buf, &mut rtts);
+        self.socket.set_read_timeout(timeout)?;
+        drained?;
+
+        let lost = rtts.iter().filter(|r| r.is_none()).count();
+        let mut received: Vec<Duration> = rtts.iter().filter_map(|&r| r).collect();
+        if received.is_empty() {
+            return Err(Error::Timeout);
+        }
+        // RFC 3550 style jitter over the round-trip times in sending order.
+        let mut jitter = 0.0;
+        for pair in received.windows(2) {
+            let d = (pair[1].as_micros() as f64 - pair[0].as_micros() as f64).abs();
+            jitter += (d - jitter) / 16.0;
+        }
+        let jitter = Duration::from_micros(jitter as u64);
+        // A frame is delivered once the echoes of all its packets are back.
+        let mut frame_latency = Vec::new();
+        let mut frames_lost = 0;
+        for (frame, &frame_start) in frames.iter().zip(&frame_sent) {
+            let arrivals: Option<Vec<Duration>> = frame
+                .clone()
+                .map(|seq| rtts[seq].map(|rtt| sent[seq] + rtt))
+                .collect();
+            match arrivals.and_then(|a| a.into_iter().max()) {
+                Some(last) => frame_latency.push(last - frame_start),
+                None => frames_lost += 1,
+            }
+        }
+        self.log_stats(
+            "Run",
+            &mut received,
+            lost,
+            jitter,
+            &mut frame_latency,
+            frames_lost,
+        );
+        let median = percentile(&received, 50.0);
+        self.rtts.extend(received);
+        self.lost += lost;
+        self.frames.extend(frame_latency);
+        self.frames_lost += frames_lost;
+        self.jitter = self.jitter.max(jitter);
+        self.runs += 1;
+        Ok(Measurement::Time(Probe {
+            target: self.target,
+            seq: self.runs,
+            bytes,
+            sent: started,
+            time: median,
+        }))
+    }
+
+    fn summary(&self) {
+        if self.rtts.is_empty() {
+            return;
+        }
+        self.log_stats(
+            "Total",
+            &mut self.rtts.clone(),
+            self.lost,
+            self.jitter,
+            &mut self.frames.clone(),
+            self.frames_lost,
+        );
+    }
+}
+
+#[test]
+fn mos_of_e_model() {
+    let g711 = Profile::VoipG711.codec().unwrap();
+    // Default R of a perfect G.711 call is 93.2, about MOS 4.4.
+    let perfect = mos(r_factor(&g711, Duration::from_millis(0), 0.0));
+    assert!((perfect - 4.41).abs() < 0.01);
+    let lossy = mos(r_factor(&g711, Duration::from_millis(50), 5.0));
+    let late = mos(r_factor(&g711, Duration::from_millis(400), 0.0));
+    assert!(lossy < perfect && late < perfect);
+    assert_eq!(mos(-10.0), 1.0);
+    assert_eq!(mos(120.0), 4.5);
+}
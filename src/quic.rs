@@ -0,0 +1,430 @@
+use super::MB;
+use crate::config::TestConfig;
+use crate::error::{Context, Error, Result};
+use crate::socket;
+use log::info;
+use rand::{Rng, SeedableRng};
+use rand_xoshiro::Xoshiro256Plus;
+use ring::digest;
+use std::fmt;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const MAX_DATAGRAM_SIZE: usize = 1350;
+
+/// What happened while establishing a QUIC connection.
+#[derive(Debug, Default)]
+pub(crate) struct Handshake {
+    pub time: Duration,
+    pub retry: bool,
+    pub version_negotiation: bool,
+    /// The request of [`Client::connect_with_request`] went out as 0-RTT early data.
+    pub early_data: bool,
+}
+
+/// Options shared by every QUIC task.
+#[derive(Clone, Debug)]
+pub struct QuicOptions {
+    /// Don't verify the server certificate chain.
+    pub insecure: bool,
+    /// PEM file of trusted CA certificates, the system roots are used if unset.
+    pub ca_file: Option<PathBuf>,
+    /// SHA-256 fingerprint of the server certificate.
+    /// If no CA file is given, the pinned certificate is trusted without verifying its chain.
+    pub pin_sha256: Option<Vec<u8>>,
+    /// Server name sent in SNI and verified against the certificate,
+    /// defaults to the host name of the target.
+    pub server_name: Option<String>,
+    /// Connection flow-control window in bytes.
+    pub max_data: u64,
+    /// Flow-control window of each stream in bytes.
+    pub max_stream_data: u64,
+    /// Number of concurrent streams the peer may open.
+    pub max_streams: u64,
+    /// Maximum UDP payload size of QUIC packets.
+    pub max_udp_payload: usize,
+    /// Congestion control algorithm, as named by quiche.
+    pub cc: String,
+    /// Directory to write a qlog trace of every connection to.
+    pub qlog_dir: Option<PathBuf>,
+}
+
+impl Default for QuicOptions {
+    fn default() -> QuicOptions {
+        QuicOptions {
+            insecure: false,
+            ca_file: None,
+            pin_sha256: None,
+            server_name: None,
+            max_data: 64 * MB as u64,
+            max_stream_data: 16 * MB as u64,
+            max_streams: 100,
+            max_udp_payload: MAX_DATAGRAM_SIZE,
+            cc: "cubic".to_string(),
+            qlog_dir: None,
+        }
+    }
+}
+
+impl fmt::Display for QuicOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "cc={} max_data={} max_stream_data={} max_streams={} max_udp_payload={}",
+            self.cc, self.max_data, self.max_stream_data, self.max_streams, self.max_udp_payload
+        )
+    }
+}
+
+/// Bundles of trusted CA certificates shipped by common systems.
+const SYSTEM_ROOTS: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/ssl/cert.pem",
+];
+
+fn system_roots() -> Result<PathBuf> {
+    if let Some(file) = std::env::var_os("SSL_CERT_FILE") {
+        return Ok(file.into());
+    }
+    SYSTEM_ROOTS
+        .iter()
+        .map(PathBuf::from)
+        .find(|p| p.is_file())
+        .ok_or_else(|| {
+            Error::InvalidInput(
+                "Can't find system CA certificates, use --ca-file or --insecure".into(),
+            )
+        })
+}
+
+/// The host name part of `addr` if it isn't an IP address.
+fn host_name(addr: &str) -> Option<String> {
+    let host = match addr.rfind(':') {
+        Some(i) => &addr[..i],
+        None => addr,
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() || host.parse::<IpAddr>().is_ok() {
+        None
+    } else {
+        Some(host.to_string())
+    }
+}
+
+/// Everything needed to connect to a QUIC server, possibly many times.
+pub(crate) struct Client {
+    config: quiche::Config,
+    test_config: TestConfig,
+    server_name: Option<String>,
+    pin_sha256: Option<Vec<u8>>,
+    max_udp_payload: usize,
+    qlog_dir: Option<PathBuf>,
+    /// TLS session of the last connection saved by [`Client::save_session`].
+    session: Option<Vec<u8>>,
+}
+
+impl Client {
+    pub fn new(addr: &str, test_config: &TestConfig, options: &QuicOptions) -> Result<Client> {
+        let timeout = test_config.timeout;
+        let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+        if options.insecure || options.ca_file.is_none() && options.pin_sha256.is_some() {
+            config.verify_peer(false);
+        } else {
+            let ca_file = match &options.ca_file {
+                Some(file) => file.clone(),
+                None => system_roots()?,
+            };
+            let ca_file = ca_file.to_str().ok_or_else(|| {
+                Error::InvalidInput(format!("Invalid CA file path: {:?}", ca_file))
+            })?;
+            config
+                .load_verify_locations_from_file(ca_file)
+                .with_context(|| format!("Failed to load CA certificates from {}", ca_file))?;
+            config.verify_peer(true);
+        }
+        config.set_application_protos(b"\x13speedtest/0.1")?;
+        config.set_disable_active_migration(true);
+        config.set_max_idle_timeout(timeout.as_millis() as u64);
+        config.set_initial_max_data(options.max_data);
+        config.set_initial_max_stream_data_bidi_local(options.max_stream_data);
+        config.set_initial_max_stream_data_bidi_remote(options.max_stream_data);
+        config.set_initial_max_stream_data_uni(options.max_stream_data);
+        config.set_initial_max_streams_bidi(options.max_streams);
+        config.set_initial_max_streams_uni(options.max_streams);
+        config.set_max_udp_payload_size(options.max_udp_payload as u64);
+        config.set_cc_algorithm_name(&options.cc).map_err(|_| {
+            Error::InvalidInput(format!(
+                "Unsupported congestion control algorithm {}, quiche supports reno and cubic",
+                options.cc
+            ))
+        })?;
+        info!("QUIC transport: {}", options);
+        Ok(Client {
+            config,
+            test_config: test_config.clone(),
+            server_name: options.server_name.clone().or_else(|| host_name(addr)),
+            pin_sha256: options.pin_sha256.clone(),
+            qlog_dir: options.qlog_dir.clone(),
+            max_udp_payload: options.max_udp_payload,
+            session: None,
+        })
+    }
+
+    /// Accept DATAGRAM frames on connections made from now on.
+    pub fn enable_dgram(&mut self) {
+        self.config.enable_dgram(true, 1000, 1000);
+    }
+
+    /// Send 0-RTT early data on connections that resume a saved session.
+    pub fn enable_early_data(&mut self) {
+        self.config.enable_early_data();
+    }
+
+    /// Resume the TLS session of `quic` on the next connections.
+    pub fn save_session(&mut self, quic: &Connection) {
+        if let Some(session) = quic.conn.session() {
+            self.session = Some(session.to_vec());
+        }
+    }
+
+    /// Connect to `peer_addr` and drive the handshake until it's established
+    /// or the timeout is reached.
+    pub fn connect(&mut self, peer_addr: SocketAddr) -> Result<Connection> {
+        let (mut quic, start) = self.start(peer_addr)?;
+        self.establish(&mut quic, start)?;
+        Ok(quic)
+    }
+
+    /// Like [`Client::connect`], and send `request` on a new stream. If the
+    /// connection resumes a saved session with early data enabled, the request
+    /// goes out as 0-RTT early data before the handshake completes.
+    pub fn connect_with_request(
+        &mut self,
+        peer_addr: SocketAddr,
+        request: &[u8],
+    ) -> Result<(Connection, u64)> {
+        let (mut quic, start) = self.start(peer_addr)?;
+        let stream = quic.open_stream();
+        // The client is in early data once its Initial carrying the session is out.
+        quic.flush().context("Failed to establish QUIC handshake")?;
+        let mut sent = 0;
+        if quic.conn.is_in_early_data() {
+            sent = match quic.conn.stream_send(stream, request, true) {
+                Ok(len) => len,
+                Err(quiche::Error::Done) => 0,
+                Err(e) => return Err(e).context("Failed to send early data"),
+            };
+            quic.handshake.early_data = sent == request.len();
+        }
+        self.establish(&mut quic, start)?;
+        if sent < request.len() {
+            quic.conn
+                .stream_send(stream, &request[sent..], true)
+                .context("Failed to send request")?;
+        }
+        Ok((quic, stream))
+    }
+
+    /// A new connection to `peer_addr` resuming the saved session, and when it started.
+    fn start(&mut self, peer_addr: SocketAddr) -> Result<(Connection, Instant)> {
+        let timeout = self.test_config.timeout;
+        let socket = socket::udp_connect(&peer_addr, &self.test_config)?;
+        let mut scid = vec![0u8; quiche::MAX_CONN_ID_LEN];
+        Xoshiro256Plus::from_entropy().fill(scid.as_mut_slice());
+        let start = Instant::now();
+        let mut conn = quiche::connect(self.server_name.as_deref(), &scid, &mut self.config)?;
+        if let Some(session) = &self.session {
+            conn.set_session(session)
+                .context("Failed to resume TLS session")?;
+        }
+        if let Some(dir) = &self.qlog_dir {
+            let id: String = scid.iter().map(|b| format!("{:02x}", b)).collect();
+            let path = dir.join(format!("client-{}.qlog", id));
+            std::fs::create_dir_all(dir)?;
+            let file = File::create(&path)
+                .with_context(|| format!("Failed to create qlog file {:?}", path))?;
+            conn.set_qlog(
+                Box::new(file),
+                "nettest".to_string(),
+                format!("nettest client connection to {}", peer_addr),
+            );
+        }
+        let quic = Connection {
+            socket,
+            conn: std::pin::Pin::into_inner(conn),
+            timeout,
+            handshake: Handshake::default(),
+            next_stream: 0,
+            buf: vec![0; 65535],
+            out: vec![0; self.max_udp_payload],
+        };
+        Ok((quic, start))
+    }
+
+    /// Drive the handshake of `quic` until it's established or the timeout is reached.
+    fn establish(&self, quic: &mut Connection, start: Instant) -> Result<()> {
+        let timeout = quic.timeout;
+        while !quic.conn.is_established() {
+            // Wait no longer than the deadline, a full poll could nearly double it.
+            let remain = timeout.checked_sub(start.elapsed()).ok_or(Error::Timeout)?;
+            quic.poll_for(remain)
+                .context("Failed to establish QUIC handshake")?;
+            if quic.conn.is_closed() {
+                return Err(Error::Protocol(format!(
+                    "connection closed, {:?}",
+                    quic.conn.stats()
+                )));
+            }
+        }
+        quic.handshake.time = start.elapsed();
+        if let Some(pin) = &self.pin_sha256 {
+            let cert = quic
+                .conn
+                .peer_cert()
+                .ok_or_else(|| Error::Protocol("Server didn't send a certificate".into()))?;
+            let fingerprint = digest::digest(&digest::SHA256, &cert);
+            if fingerprint.as_ref() != pin.as_slice() {
+                return Err(Error::Protocol(format!(
+                    "Certificate fingerprint mismatch: {}",
+                    hex(fingerprint.as_ref())
+                )));
+            }
+        }
+        quic.flush().context("Failed to establish QUIC handshake")?;
+        Ok(())
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// A client connection together with its socket.
+///
+/// Every QUIC task drives the connection through [`Connection::poll`],
+/// which sends whatever quiche has queued, waits for packets until the next
+/// timer fires and feeds them back to quiche.
+pub(crate) struct Connection {
+    socket: UdpSocket,
+    conn: Box<quiche::Connection>,
+    timeout: Duration,
+    handshake: Handshake,
+    next_stream: u64,
+    buf: Vec<u8>,
+    out: Vec<u8>,
+}
+
+impl Connection {
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+
+    /// Allocate the next client-initiated bidirectional stream.
+    pub fn open_stream(&mut self) -> u64 {
+        let id = self.next_stream;
+        self.next_stream += 4;
+        id
+    }
+
+    /// Send every packet quiche has queued.
+    pub fn flush(&mut self) -> Result<()> {
+        loop {
+            let write = match self.conn.send(&mut self.out) {
+                Ok(v) => v,
+                Err(quiche::Error::Done) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            self.socket.send(&self.out[..write])?;
+        }
+    }
+
+    /// Flush pending packets, then block until packets arrive or the next
+    /// quiche timer expires, and process everything that has arrived.
+    pub fn poll(&mut self) -> Result<()> {
+        self.poll_for(self.timeout)
+    }
+
+    /// Like [`Connection::poll`], but block for at most `max`.
+    pub fn poll_for(&mut self, max: Duration) -> Result<()> {
+        self.flush()?;
+        let wait = self.conn.timeout().map_or(max, |t| t.min(max));
+        if wait == Duration::from_secs(0) {
+            self.conn.on_timeout();
+            return Ok(());
+        }
+        self.socket.set_read_timeout(Some(wait))?;
+        match self.socket.recv(&mut self.buf) {
+            Ok(len) => self.process(len)?,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                self.conn.on_timeout();
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        }
+        // Drain whatever else is already queued on the socket.
+        self.socket.set_nonblocking(true)?;
+        let result = loop {
+            match self.socket.recv(&mut self.buf) {
+                Ok(len) => {
+                    if let Err(e) = self.process(len) {
+                        break Err(e);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e.into()),
+            }
+        };
+        self.socket.set_nonblocking(false)?;
+        result
+    }
+
+    fn process(&mut self, len: usize) -> Result<()> {
+        if !self.conn.is_established() {
+            let buf = &mut self.buf[..len];
+            if let Ok(hdr) = quiche::Header::from_slice(buf, quiche::MAX_CONN_ID_LEN) {
+                match hdr.ty {
+                    quiche::Type::Retry => self.handshake.retry = true,
+                    quiche::Type::VersionNegotiation => self.handshake.version_negotiation = true,
+                    _ => {}
+                }
+            }
+        }
+        // Process potentially coalesced packets.
+        match self.conn.recv(&mut self.buf[..len]) {
+            Ok(_) | Err(quiche::Error::Done) => Ok(()),
+            Err(e) => Err(e).context("Fail to receive from peer"),
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if !self.conn.is_closed() {
+            let _ = self.conn.close(true, 0x00, b"");
+            let _ = self.flush();
+        }
+    }
+}
+
+impl Deref for Connection {
+    type Target = quiche::Connection;
+    fn deref(&self) -> &quiche::Connection {
+        &self.conn
+    }
+}
+
+impl DerefMut for Connection {
+    fn deref_mut(&mut self) -> &mut quiche::Connection {
+        &mut self.conn
+    }
+}
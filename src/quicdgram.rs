@@ -0,0 +1,164 @@
+use crate::config::{Pattern, TestConfig};
+use crate::error::{Context, Error, Result};
+use crate::quic::{self, QuicOptions};
+use crate::socket;
+use crate::{Measurement, Probe, Task};
+use log::info;
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Reordering and duplication of the replies.
+#[derive(Default)]
+struct Replies {
+    /// Highest seq a reply was received of.
+    highest: u64,
+    seen: BTreeSet<u64>,
+    /// Replies received after the reply of a later probe.
+    reordered: usize,
+    /// Replies of any probe received more than once.
+    duplicated: usize,
+}
+
+impl Replies {
+    /// Count the reply of `seq`, false if it's a duplicate.
+    fn receive(&mut self, seq: u64) -> bool {
+        if !self.seen.insert(seq) {
+            self.duplicated += 1;
+            return false;
+        }
+        if seq < self.highest {
+            self.reordered += 1;
+        }
+        self.highest = self.highest.max(seq);
+        true
+    }
+}
+
+pub struct QuicdgramTask {
+    target: SocketAddr,
+    quic: quic::Connection,
+    timeout: Duration,
+    seq: u64,
+    size: usize,
+    pattern: Pattern,
+    replies: Replies,
+    /// Replies received after their probe timed out, the probe still counts as lost.
+    late: usize,
+}
+
+impl QuicdgramTask {
+    pub fn new(addr: &str, config: &TestConfig, options: &QuicOptions) -> Result<QuicdgramTask> {
+        let target = socket::resolve(addr, config)?;
+        let format_target = target.to_string();
+        if format_target != addr {
+            info!("Ping to {} ({}) using QUIC DATAGRAM", addr, format_target);
+        } else {
+            info!("Ping to {} using QUIC DATAGRAM", format_target);
+        }
+        let mut client = quic::Client::new(addr, config, options)?;
+        client.enable_dgram();
+        let quic = client.connect(target)?;
+        let max_len = quic
+            .dgram_max_writable_len()
+            .ok_or_else(|| Error::Protocol("Server doesn't support QUIC DATAGRAM".into()))?;
+        let size = config.size.max(8);
+        if size > max_len {
+            return Err(Error::InvalidInput(format!(
+                "Payload is too large: {} bytes, at most {} bytes fit in a DATAGRAM frame",
+                size, max_len
+            )));
+        }
+        Ok(Self {
+            target,
+            quic,
+            timeout: config.timeout,
+            seq: 0,
+            size,
+            pattern: config.pattern,
+            replies: Replies::default(),
+            late: 0,
+        })
+    }
+}
+
+impl Task for QuicdgramTask {
+    fn run(&mut self) -> Result<Measurement> {
+        self.seq += 1;
+        let mut buffer = vec![0u8; self.size];
+        buffer[..8].copy_from_slice(&self.seq.to_le_bytes());
+        self.pattern.fill(&mut buffer[8..]);
+        let start = Instant::now();
+        self.quic
+            .dgram_send(&buffer)
+            .context("Failed to send DATAGRAM")?;
+        let mut time = None;
+        loop {
+            let remain = match self.timeout.checked_sub(start.elapsed()) {
+                Some(remain) if time.is_none() => remain,
+                _ => break,
+            };
+            self.quic.poll_for(remain)?;
+            if self.quic.is_closed() {
+                return Err(Error::Protocol(format!(
+                    "connection closed, {:?}",
+                    self.quic.stats()
+                )));
+            }
+            while let Ok(len) = self.quic.dgram_recv(&mut buffer) {
+                if len < 8 {
+                    continue;
+                }
+                let seq = u64::from_le_bytes(buffer[..8].try_into().unwrap());
+                if !self.replies.receive(seq) {
+                    continue;
+                }
+                if seq == self.seq {
+                    time = Some(start.elapsed());
+                } else {
+                    self.late += 1;
+                    info!(
+                        "Reply of seq={} arrived after it timed out, seq={} was sent",
+                        seq, self.seq
+                    );
+                }
+            }
+        }
+        let time = time.ok_or(Error::Timeout)?;
+        info!(
+            "{} bytes from {}: seq={} time={:?}",
+            self.size, self.target, self.seq, time
+        );
+        Ok(Measurement::Time(Probe::new(
+            self.target,
+            self.seq,
+            self.size,
+            time,
+        )))
+    }
+
+    fn summary(&self) {
+        info!(
+            "{} replies reordered, {} duplicated, {} late and counted as lost, {:?}",
+            self.replies.reordered,
+            self.replies.duplicated,
+            self.late,
+            self.quic.stats()
+        );
+    }
+}
+
+#[test]
+fn replies() {
+    let mut replies = Replies::default();
+    // 2 overtakes 1, 3 arrives twice, 1 arrives again after that.
+    for &seq in &[2, 1, 3, 3, 1, 5] {
+        replies.receive(seq);
+    }
+    assert_eq!(replies.reordered, 1);
+    assert_eq!(replies.duplicated, 2);
+    // 4 was overtaken by 5.
+    assert!(replies.receive(4));
+    assert_eq!(replies.reordered, 2);
+}
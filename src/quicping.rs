@@ -0,0 +1,121 @@
+use crate::config::TestConfig;
+use crate::error::{Error, Result};
+use crate::quic::{self, QuicOptions};
+use crate::socket;
+use crate::{Measurement, Probe, Task};
+use log::info;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Request of 0-RTT probes, the server answers it with a single byte.
+const REQUEST: &[u8] = b"DOWNLOAD 1\r\n";
+
+pub struct QuicpingTask {
+    target: SocketAddr,
+    client: quic::Client,
+    timeout: Duration,
+    /// Resume the session of the previous run and send a request as 0-RTT early data.
+    zero_rtt: bool,
+    seq: u16,
+}
+
+impl QuicpingTask {
+    pub fn new(addr: &str, config: &TestConfig, options: &QuicOptions) -> Result<QuicpingTask> {
+        let target = socket::resolve(addr, config)?;
+        let format_target = target.to_string();
+        if format_target != addr {
+            info!("Ping to {} ({}) using QUIC handshake", addr, format_target);
+        } else {
+            info!("Ping to {} using QUIC handshake", format_target);
+        }
+        Ok(Self {
+            target,
+            client: quic::Client::new(addr, config, options)?,
+            timeout: config.timeout,
+            zero_rtt: false,
+            seq: 0,
+        })
+    }
+
+    /// Measure the time until the reply to a request sent as 0-RTT early data,
+    /// resuming the session of the previous run. The first run has no session
+    /// yet and sends the request after a full handshake.
+    pub fn zero_rtt(mut self, zero_rtt: bool) -> QuicpingTask {
+        if zero_rtt {
+            self.client.enable_early_data();
+        }
+        self.zero_rtt = zero_rtt;
+        self
+    }
+
+    /// Connect sending the request, and wait for its reply.
+    fn request(&mut self) -> Result<(quic::Connection, Duration)> {
+        let start = Instant::now();
+        let (mut quic, stream) = self.client.connect_with_request(self.target, REQUEST)?;
+        let mut buf = [0u8; 64];
+        while !quic.stream_finished(stream) {
+            let remain = self
+                .timeout
+                .checked_sub(start.elapsed())
+                .ok_or(Error::Timeout)?;
+            quic.poll_for(remain)?;
+            if quic.is_closed() {
+                return Err(Error::Protocol(format!(
+                    "connection closed, {:?}",
+                    quic.stats()
+                )));
+            }
+            while quic.stream_recv(stream, &mut buf).is_ok() {}
+        }
+        let time = start.elapsed();
+        // The session ticket arrives after the handshake, by now it's there.
+        self.client.save_session(&quic);
+        Ok((quic, time))
+    }
+}
+
+impl Task for QuicpingTask {
+    fn run(&mut self) -> Result<Measurement> {
+        self.seq = self.seq.wrapping_add(1);
+        // A fresh socket per run, so every handshake starts from a new 4-tuple.
+        let (quic, reply) = if self.zero_rtt {
+            let (quic, time) = self.request()?;
+            (quic, Some(time))
+        } else {
+            (self.client.connect(self.target)?, None)
+        };
+        let handshake = quic.handshake();
+        let mut events = String::new();
+        if handshake.retry {
+            events.push_str(" retry");
+        }
+        if handshake.version_negotiation {
+            events.push_str(" version-negotiation");
+        }
+        if handshake.early_data {
+            events.push_str(" 0-rtt");
+        }
+        info!(
+            "Handshake with {}: seq={} time={:?}{}",
+            self.target, self.seq, handshake.time, events
+        );
+        // With 0-RTT the reply is what gets faster, not the handshake.
+        let time = match reply {
+            Some(time) => {
+                info!(
+                    "Reply from {}: seq={} time={:?}",
+                    self.target, self.seq, time
+                );
+                time
+            }
+            None => handshake.time,
+        };
+        info!("{:?}", quic.stats());
+        Ok(Measurement::Time(Probe::new(
+            self.target,
+            self.seq as u64,
+            0,
+            time,
+        )))
+    }
+}
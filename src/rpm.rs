@@ -0,0 +1,374 @@
+use crate::asynctask::timeout;
+use crate::config::TestConfig;
+use crate::error::{Error, Result};
+use crate::socket::{self, TcpOptions};
+use crate::wire::{self, LOAD_DATA, LOAD_SMALL};
+use crate::{Measurement, Probe, Task};
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use log::info;
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{self, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Load flows added at every ramp-up step.
+const FLOWS_PER_STEP: usize = 4;
+const MAX_FLOWS: usize = 16;
+/// Length of one ramp-up step.
+const STEP: Duration = Duration::from_secs(1);
+/// Goodput is saturated once a step adds less than this to the average of the previous ones.
+const SATURATION: f64 = 0.05;
+/// Number of previous steps goodput is compared with.
+const MOVING_AVERAGE: usize = 4;
+const MAX_STEPS: usize = 20;
+/// Time spent probing once goodput is saturated.
+const PROBE_DURATION: Duration = Duration::from_secs(5);
+const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Responsiveness under working conditions, in Round-trips Per Minute,
+/// following draft-ietf-ippm-responsiveness.
+pub struct RpmTask {
+    addr: SocketAddr,
+    config: TestConfig,
+    options: TcpOptions,
+    /// RPM of every run.
+    rpm: Vec<f64>,
+}
+
+/// Download flows saturating the link until dropped. Self probes request the
+/// small object on the flows, the server sends it between the bulk data.
+struct Load {
+    bytes: Arc<AtomicUsize>,
+    flows: Vec<Flow>,
+    /// Flow of the next self probe.
+    next: usize,
+    /// Round-trip times of the answered self probes.
+    replies: Arc<Mutex<Vec<Duration>>>,
+}
+
+struct Flow {
+    stream: TcpStream,
+    /// Send times of the self probes waiting for their small object.
+    pending: Arc<Mutex<VecDeque<Instant>>>,
+}
+
+impl Load {
+    fn new() -> Load {
+        Load {
+            bytes: Arc::new(AtomicUsize::new(0)),
+            flows: Vec::new(),
+            next: 0,
+            replies: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    async fn add_flow(
+        &mut self,
+        addr: &SocketAddr,
+        config: &TestConfig,
+        options: &TcpOptions,
+    ) -> Result<()> {
+        let mut stream = TcpStream::from(connect_blocking(addr, config, options).await?);
+        timeout(config.timeout, stream.write_all(b"LOAD\r\n")).await?;
+        let mut header = [0u8; wire::FRAME_HEADER_LEN];
+        timeout(config.timeout, stream.read_exact(&mut header)).await?;
+        if header.starts_with(b"ERROR") {
+            return Err(Error::Protocol("Server doesn't support LOAD".into()));
+        }
+        let pending = Arc::new(Mutex::new(VecDeque::new()));
+        let reader = receive(
+            stream.clone(),
+            header,
+            config.timeout,
+            Arc::clone(&self.bytes),
+            Arc::clone(&pending),
+            Arc::clone(&self.replies),
+        );
+        async_std::task::spawn(async move {
+            if let Err(e) = reader.await {
+                info!("Load flow failed: {}", e);
+            }
+        });
+        self.flows.push(Flow { stream, pending });
+        Ok(())
+    }
+
+    /// Request the small object on the next flow, its reader records the round trip.
+    async fn probe(&mut self, dur: Duration) -> Result<()> {
+        self.next += 1;
+        let flow = &self.flows[(self.next - 1) % self.flows.len()];
+        flow.pending.lock().unwrap().push_back(Instant::now());
+        let mut stream = &flow.stream;
+        if let Err(e) = timeout(dur, stream.write_all(b"SMALL\r\n")).await {
+            flow.pending.lock().unwrap().pop_back();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Number of self probes still waiting for their small object.
+    fn unanswered(&self) -> usize {
+        self.flows
+            .iter()
+            .map(|flow| flow.pending.lock().unwrap().len())
+            .sum()
+    }
+}
+
+impl Drop for Load {
+    fn drop(&mut self) {
+        // The server stops sending once the flow is closed, and its reader sees the end.
+        for flow in &self.flows {
+            let _ = flow.stream.shutdown(net::Shutdown::Both);
+        }
+    }
+}
+
+/// Read the frames of a load flow, starting with the one of `header`, until it's closed.
+async fn receive(
+    mut stream: TcpStream,
+    mut header: [u8; wire::FRAME_HEADER_LEN],
+    dur: Duration,
+    bytes: Arc<AtomicUsize>,
+    pending: Arc<Mutex<VecDeque<Instant>>>,
+    replies: Arc<Mutex<Vec<Duration>>>,
+) -> Result<()> {
+    let mut buffer = vec![0u8; 128 * 1024];
+    loop {
+        let (kind, mut len) = wire::parse_frame_header(&header);
+        if kind != LOAD_DATA && kind != LOAD_SMALL {
+            return Err(Error::UnexpectedReply(format!(
+                "Unknown frame type {:?}",
+                kind as char
+            )));
+        }
+        while len > 0 {
+            let read = len.min(buffer.len());
+            let read = timeout(dur, stream.read(&mut buffer[..read])).await?;
+            if read == 0 {
+                return Ok(());
+            }
+            bytes.fetch_add(read, Ordering::Relaxed);
+            len -= read;
+        }
+        if kind == LOAD_SMALL {
+            if let Some(sent) = pending.lock().unwrap().pop_front() {
+                replies.lock().unwrap().push(sent.elapsed());
+            }
+        }
+        match timeout(dur, stream.read_exact(&mut header)).await {
+            Err(Error::Io(_, e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        }
+    }
+}
+
+/// `socket::tcp_connect` on the thread pool for blocking work.
+async fn connect_blocking(
+    addr: &SocketAddr,
+    config: &TestConfig,
+    options: &TcpOptions,
+) -> Result<net::TcpStream> {
+    let (addr, config, options) = (*addr, config.clone(), options.clone());
+    async_std::task::spawn_blocking(move || socket::tcp_connect(&addr, &config, &options)).await
+}
+
+fn connect(
+    addr: &SocketAddr,
+    config: &TestConfig,
+    options: &TcpOptions,
+) -> Result<BufReader<net::TcpStream>> {
+    let stream = socket::tcp_connect(addr, config, options)?;
+    stream.set_read_timeout(Some(config.timeout))?;
+    stream.set_write_timeout(Some(config.timeout))?;
+    Ok(BufReader::new(stream))
+}
+
+/// Request the small object on `stream` and wait for it.
+fn small(stream: &mut BufReader<net::TcpStream>) -> Result<Duration> {
+    let start = Instant::now();
+    stream.get_mut().write_all(b"SMALL\r\n")?;
+    let mut line = String::new();
+    stream.read_line(&mut line)?;
+    if !line.ends_with('\n') || line.starts_with("ERROR") {
+        return Err(Error::Protocol("Server doesn't serve small objects".into()));
+    }
+    Ok(start.elapsed())
+}
+
+/// Handshake and request time of a foreign probe, on a new connection.
+fn foreign(
+    addr: SocketAddr,
+    config: &TestConfig,
+    options: &TcpOptions,
+) -> Result<(Duration, Result<Duration>)> {
+    let start = Instant::now();
+    let mut stream = connect(&addr, config, options)?;
+    let handshake = start.elapsed();
+    let request = small(&mut stream);
+    let _ = stream.get_mut().write_all(b"QUIT\r\n");
+    Ok((handshake, request))
+}
+
+/// Mean of `times` without the highest 5%.
+fn trimmed_mean(times: &mut [Duration]) -> Duration {
+    times.sort();
+    let len = (times.len() * 95 / 100).clamp(1, times.len());
+    times[..len].iter().sum::<Duration>() / len as u32
+}
+
+fn ms(t: Duration) -> f64 {
+    t.as_micros() as f64 / 1000.0
+}
+
+impl RpmTask {
+    pub fn new(server: &str, config: &TestConfig, options: &TcpOptions) -> Result<RpmTask> {
+        info!("Responsiveness test, connecting to {}", server);
+        Ok(RpmTask {
+            addr: socket::resolve(server, config)?,
+            config: config.clone(),
+            options: options.clone(),
+            rpm: Vec::new(),
+        })
+    }
+
+    /// Add flows until goodput stops growing, returns the saturated goodput in Mbps.
+    async fn ramp_up(&self, load: &mut Load) -> Result<f64> {
+        let mut goodput: Vec<f64> = Vec::new();
+        for _ in 0..MAX_STEPS {
+            if load.flows.len() < MAX_FLOWS {
+                for _ in 0..FLOWS_PER_STEP {
+                    load.add_flow(&self.addr, &self.config, &self.options)
+                        .await?;
+                }
+            }
+            let start = Instant::now();
+            let before = load.bytes.load(Ordering::Relaxed);
+            async_std::task::sleep(STEP).await;
+            let len = load.bytes.load(Ordering::Relaxed) - before;
+            let mbps = len as f64 / (start.elapsed().as_micros() as f64) * 8.0;
+            info!("{} flows, goodput: {:.3} Mbps", load.flows.len(), mbps);
+            let previous = &goodput[goodput.len().saturating_sub(MOVING_AVERAGE)..];
+            if previous.len() == MOVING_AVERAGE {
+                let average = previous.iter().sum::<f64>() / previous.len() as f64;
+                if mbps < average * (1.0 + SATURATION) {
+                    return Ok(mbps);
+                }
+            }
+            goodput.push(mbps);
+        }
+        info!("Goodput didn't saturate after {} steps", MAX_STEPS);
+        Ok(goodput.last().copied().unwrap_or_default())
+    }
+
+    async fn run_async(&mut self) -> Result<Measurement> {
+        let mut load = Load::new();
+        let goodput = self.ramp_up(&mut load).await?;
+        // Self probes go over the load flows, foreign probes open a new connection every time.
+        let mut tcp_foreign = Vec::new();
+        let mut http_foreign = Vec::new();
+        let mut foreign_error = None;
+        let mut self_failed = 0;
+        let mut self_error = None;
+        let started = SystemTime::now();
+        let start = Instant::now();
+        while start.elapsed() < PROBE_DURATION {
+            let probe_start = Instant::now();
+            if let Err(e) = load.probe(self.config.timeout).await {
+                info!("Self probe failed: {}", e);
+                self_failed += 1;
+                self_error = Some(e);
+            }
+            let (addr, config, options) = (self.addr, self.config.clone(), self.options.clone());
+            match async_std::task::spawn_blocking(move || foreign(addr, &config, &options)).await {
+                Ok((handshake, request)) => {
+                    tcp_foreign.push(handshake);
+                    match request {
+                        Ok(time) => http_foreign.push(time),
+                        Err(e) => {
+                            info!("Foreign probe failed: {}", e);
+                            foreign_error = Some(e);
+                        }
+                    }
+                }
+                Err(e) => {
+                    info!("Foreign probe failed: {}", e);
+                    foreign_error = Some(e);
+                }
+            }
+            if let Some(wait) = PROBE_INTERVAL.checked_sub(probe_start.elapsed()) {
+                async_std::task::sleep(wait).await;
+            }
+        }
+        // Self probes unanswered within the timeout count as failed.
+        let deadline = Instant::now() + self.config.timeout;
+        while load.unanswered() > 0 && Instant::now() < deadline {
+            async_std::task::sleep(PROBE_INTERVAL / 10).await;
+        }
+        self_failed += load.unanswered();
+        let mut http_self = load.replies.lock().unwrap().clone();
+        drop(load);
+        if self_failed > 0 {
+            info!(
+                "{} of {} self probes failed",
+                self_failed,
+                self_failed + http_self.len()
+            );
+        }
+        if tcp_foreign.is_empty() || http_foreign.is_empty() {
+            let e = foreign_error.expect("At least one foreign probe is sent");
+            return Err(e.context("All foreign probes failed"));
+        }
+        if http_self.is_empty() {
+            let e = self_error.unwrap_or(Error::Timeout);
+            return Err(e.context("All self probes failed"));
+        }
+        let tcp_foreign = trimmed_mean(&mut tcp_foreign);
+        let http_foreign = trimmed_mean(&mut http_foreign);
+        let http_self = trimmed_mean(&mut http_self);
+        // Without TLS the foreign probes' share is split between TCP and the request.
+        let time = (tcp_foreign + http_foreign) / 4 + http_self / 2;
+        let rpm = 60_000.0 / ms(time);
+        info!(
+            "Foreign TCP handshake: {:.3} ms, foreign request: {:.3} ms, self request: {:.3} ms",
+            ms(tcp_foreign),
+            ms(http_foreign),
+            ms(http_self)
+        );
+        info!(
+            "Goodput: {:.3} Mbps, responsiveness: {:.0} RPM",
+            goodput, rpm
+        );
+        self.rpm.push(rpm);
+        Ok(Measurement::Time(Probe {
+            target: self.addr,
+            seq: self.rpm.len() as u64,
+            bytes: 0,
+            sent: started,
+            time,
+        }))
+    }
+}
+
+impl Task for RpmTask {
+    fn run(&mut self) -> Result<Measurement> {
+        async_std::task::block_on(self.run_async())
+    }
+
+    fn summary(&self) {
+        if self.rpm.is_empty() {
+            return;
+        }
+        let mut rpm = self.rpm.clone();
+        rpm.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        info!(
+            "RPM min/avg/max = {:.0}/{:.0}/{:.0}",
+            rpm[0],
+            rpm.iter().sum::<f64>() / rpm.len() as f64,
+            rpm[rpm.len() - 1]
+        );
+    }
+}
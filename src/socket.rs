@@ -0,0 +1,303 @@
+use crate::config::TestConfig;
+use crate::error::{Context, Error, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::mem::size_of;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+
+/// Socket options of TCP connections, applied before connecting.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TcpOptions {
+    /// Congestion control algorithm, e.g. `cubic`, `bbr` or `reno` (Linux only).
+    pub congestion: Option<String>,
+    /// `SO_SNDBUF` in bytes.
+    pub send_buffer: Option<usize>,
+    /// `SO_RCVBUF` in bytes.
+    pub recv_buffer: Option<usize>,
+    /// Disable Nagle's algorithm.
+    pub nodelay: bool,
+    /// `TCP_MAXSEG` in bytes.
+    pub mss: Option<u32>,
+    /// `SO_MAX_PACING_RATE` in bytes per second (Linux only).
+    pub max_pacing_rate: Option<u64>,
+}
+
+fn setsockopt<T>(fd: RawFd, level: libc::c_int, name: libc::c_int, value: &T) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Use congestion control algorithm `name` on the socket.
+#[cfg(target_os = "linux")]
+pub fn set_congestion<S: AsRawFd>(socket: &S, name: &str) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_CONGESTION,
+            name.as_ptr() as *const libc::c_void,
+            name.len() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Congestion control algorithm in use on the socket.
+#[cfg(target_os = "linux")]
+pub(crate) fn congestion<S: AsRawFd>(socket: &S) -> io::Result<String> {
+    let mut name = [0u8; 16];
+    let mut len = name.len() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_CONGESTION,
+            name.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let name = &name[..len as usize];
+    let end = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    Ok(String::from_utf8_lossy(&name[..end]).into_owned())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_congestion<S: AsRawFd>(_socket: &S, _name: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "TCP_CONGESTION is only supported on Linux",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn congestion<S: AsRawFd>(_socket: &S) -> io::Result<String> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "TCP_CONGESTION is only supported on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn set_max_pacing_rate(fd: RawFd, rate: u64) -> io::Result<()> {
+    // Linux 4.20+ takes a 64 bit value, older kernels only 32 bit.
+    if rate > u64::from(u32::MAX) {
+        setsockopt(fd, libc::SOL_SOCKET, libc::SO_MAX_PACING_RATE, &rate)
+    } else {
+        setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_MAX_PACING_RATE,
+            &(rate as u32),
+        )
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_max_pacing_rate(_fd: RawFd, _rate: u64) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "SO_MAX_PACING_RATE is only supported on Linux",
+    ))
+}
+
+/// First address of `addr` in the address family of `config`.
+pub(crate) fn resolve(addr: &str, config: &TestConfig) -> Result<SocketAddr> {
+    let family = config.resolve_family();
+    addr.to_socket_addrs()
+        .map_err(|e| Error::Resolve(format!("Can't resolve IP address of {}: {}", addr, e)))?
+        .find(|a| family.matches(a))
+        .ok_or_else(|| Error::Resolve(format!("Don't have {:?} address of {}", family, addr)))
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device(fd: RawFd, interface: &str) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            interface.as_ptr() as *const libc::c_void,
+            interface.len() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_fd: RawFd, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "SO_BINDTODEVICE is only supported on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn set_mark(fd: RawFd, mark: u32) -> io::Result<()> {
+    setsockopt(fd, libc::SOL_SOCKET, libc::SO_MARK, &mark)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_mark(_fd: RawFd, _mark: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "SO_MARK is only supported on Linux",
+    ))
+}
+
+/// Apply the interface, VRF, fwmark, DSCP/ECN and source address of `config`
+/// to a new socket for `target`.
+pub(crate) fn prepare(socket: &Socket, target: &SocketAddr, config: &TestConfig) -> Result<()> {
+    let fd = socket.as_raw_fd();
+    // Both are a device the socket is bound to, a VRF is the master device of its interfaces.
+    let device = match (&config.interface, &config.vrf) {
+        (Some(_), Some(_)) => {
+            return Err(Error::InvalidInput(
+                "Interface and VRF can't be used together".into(),
+            ))
+        }
+        (Some(interface), None) => Some(("interface", interface)),
+        (None, Some(vrf)) => Some(("VRF", vrf)),
+        (None, None) => None,
+    };
+    if let Some((kind, name)) = device {
+        if name.is_empty() || name.len() >= libc::IFNAMSIZ {
+            return Err(Error::InvalidInput(format!(
+                "Invalid {} name {:?}",
+                kind, name
+            )));
+        }
+        bind_device(fd, name).with_context(|| format!("Failed to bind to {} {}", kind, name))?;
+    }
+    if let Some(mark) = config.fwmark {
+        set_mark(fd, mark).with_context(|| format!("Failed to set fwmark {:#x}", mark))?;
+    }
+    if let Some(tos) = config.tos()? {
+        let tos = libc::c_int::from(tos);
+        if target.is_ipv4() {
+            setsockopt(fd, libc::IPPROTO_IP, libc::IP_TOS, &tos)
+        } else {
+            setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, &tos)
+        }
+        .context("Failed to set DSCP/ECN")?;
+    }
+    if let Some(source) = config.source {
+        if source.is_ipv4() != target.is_ipv4() {
+            return Err(Error::InvalidInput(format!(
+                "Source address {} and target {} are of different families",
+                source, target
+            )));
+        }
+        socket
+            .bind(&SocketAddr::new(source, 0).into())
+            .with_context(|| format!("Failed to bind to {}", source))?;
+    }
+    Ok(())
+}
+
+/// Socket of `ty` and `protocol` for `target`, prepared with `config`.
+pub(crate) fn new_socket(
+    target: &SocketAddr,
+    ty: Type,
+    protocol: Protocol,
+    config: &TestConfig,
+) -> Result<Socket> {
+    let domain = if target.is_ipv4() {
+        Domain::IPV4
+    } else {
+        Domain::IPV6
+    };
+    let socket = Socket::new(domain, ty, Some(protocol))?;
+    prepare(&socket, target, config)?;
+    Ok(socket)
+}
+
+/// Connect to `addr` with `config` and `options` applied to the socket.
+pub(crate) fn tcp_connect(
+    addr: &SocketAddr,
+    config: &TestConfig,
+    options: &TcpOptions,
+) -> Result<TcpStream> {
+    let socket = new_socket(addr, Type::STREAM, Protocol::TCP, config)?;
+    let fd = socket.as_raw_fd();
+    if let Some(name) = &options.congestion {
+        set_congestion(&socket, name)
+            .with_context(|| format!("Failed to use congestion control {}", name))?;
+    }
+    if let Some(size) = options.send_buffer {
+        setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_SNDBUF,
+            &(size as libc::c_int),
+        )
+        .context("Failed to set SO_SNDBUF")?;
+    }
+    if let Some(size) = options.recv_buffer {
+        setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_RCVBUF,
+            &(size as libc::c_int),
+        )
+        .context("Failed to set SO_RCVBUF")?;
+    }
+    if options.nodelay {
+        setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_NODELAY,
+            &(1 as libc::c_int),
+        )
+        .context("Failed to set TCP_NODELAY")?;
+    }
+    if let Some(mss) = options.mss {
+        setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_MAXSEG,
+            &(mss as libc::c_int),
+        )
+        .context("Failed to set TCP_MAXSEG")?;
+    }
+    if let Some(rate) = options.max_pacing_rate {
+        set_max_pacing_rate(fd, rate).context("Failed to set SO_MAX_PACING_RATE")?;
+    }
+    socket.connect_timeout(&(*addr).into(), config.timeout)?;
+    Ok(unsafe { TcpStream::from_raw_fd(socket.into_raw_fd()) })
+}
+
+/// UDP socket connected to `target`, with the timeout of `config` for reads and writes.
+pub(crate) fn udp_connect(target: &SocketAddr, config: &TestConfig) -> Result<UdpSocket> {
+    let socket = new_socket(target, Type::DGRAM, Protocol::UDP, config)?;
+    socket
+        .connect(&(*target).into())
+        .with_context(|| format!("Failed to connect to {}", target))?;
+    let socket = unsafe { UdpSocket::from_raw_fd(socket.into_raw_fd()) };
+    socket.set_read_timeout(Some(config.timeout))?;
+    socket.set_write_timeout(Some(config.timeout))?;
+    Ok(socket)
+}
@@ -0,0 +1,176 @@
+use crate::cpu::CpuUsage;
+use crate::tcpinfo::TcpInfo;
+use crate::Measurement;
+use log::info;
+use std::time::Duration;
+
+/// Round-trip times of the latency probes among the results.
+#[derive(Clone, Copy, Debug)]
+pub struct Latency {
+    /// Sum of all round-trip times.
+    pub total: Duration,
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+    /// Standard deviation, `mdev` of ping.
+    pub mdev: Duration,
+}
+
+/// Throughput of the transfers among the results, in Mbps.
+#[derive(Clone, Copy, Debug)]
+pub struct Speed {
+    pub bytes: usize,
+    pub time: Duration,
+    pub min: f64,
+    pub avg: f64,
+    pub max: f64,
+}
+
+/// Summary of the results of a task, what is printed after all runs.
+#[derive(Clone, Copy, Debug)]
+pub struct Stats {
+    /// Runs, including the failed ones.
+    pub sent: usize,
+    pub received: usize,
+    pub latency: Option<Latency>,
+    pub speed: Option<Speed>,
+}
+
+fn mbps(bytes: usize, time: Duration) -> f64 {
+    (bytes * 8) as f64 / time.as_micros().max(1) as f64
+}
+
+impl Stats {
+    /// Statistics of `results` and `failed` more runs without one.
+    pub fn new<'a, I: IntoIterator<Item = &'a Measurement>>(results: I, failed: usize) -> Stats {
+        let mut times = Vec::new();
+        let mut transfers = Vec::new();
+        for result in results {
+            match result {
+                Measurement::Time(probe) => times.push(probe.time),
+                Measurement::Speed(transfer) => transfers.push((transfer.bytes, transfer.time)),
+            }
+        }
+        let received = times.len() + transfers.len();
+        let latency = if times.is_empty() {
+            None
+        } else {
+            let total: Duration = times.iter().sum();
+            let avg = total / times.len() as u32;
+            let variance = times
+                .iter()
+                .map(|t| t.as_nanos() as i128 - avg.as_nanos() as i128)
+                .map(|t| t * t)
+                .sum::<i128>() as f64
+                / times.len() as f64;
+            Some(Latency {
+                total,
+                min: *times.iter().min().unwrap(),
+                avg,
+                max: *times.iter().max().unwrap(),
+                mdev: Duration::from_nanos(variance.sqrt() as u64),
+            })
+        };
+        let speed = if transfers.is_empty() {
+            None
+        } else {
+            let speeds: Vec<f64> = transfers.iter().map(|&(b, t)| mbps(b, t)).collect();
+            let bytes = transfers.iter().map(|t| t.0).sum();
+            let time = transfers.iter().map(|t| t.1).sum();
+            Some(Speed {
+                bytes,
+                time,
+                min: speeds.iter().cloned().fold(f64::INFINITY, f64::min),
+                avg: mbps(bytes, time),
+                max: speeds.iter().cloned().fold(0.0, f64::max),
+            })
+        };
+        Stats {
+            sent: received + failed,
+            received,
+            latency,
+            speed,
+        }
+    }
+
+    /// Percent of runs that failed.
+    pub fn loss(&self) -> f64 {
+        100.0 * (self.sent - self.received) as f64 / self.sent.max(1) as f64
+    }
+
+    pub fn log(&self) {
+        let ms = |t: Duration| format!("{:.3}", t.as_micros() as f64 / 1000.0);
+        if let Some(speed) = &self.speed {
+            info!(
+                "{} MiB transmitted in {:?}",
+                speed.bytes / (1024 * 1024),
+                speed.time
+            );
+            info!(
+                "Speed min/avg/max {:.3}/{:.3}/{:.3}Mbps",
+                speed.min, speed.avg, speed.max
+            );
+        }
+        if let Some(latency) = &self.latency {
+            info!(
+                "{} packets transmitted, {} received, {:.2}% packet loss, time {} ms",
+                self.sent,
+                self.received,
+                self.loss(),
+                ms(latency.total)
+            );
+            info!(
+                "rtt min/avg/max/mdev = {}/{}/{}/{} ms",
+                ms(latency.min),
+                ms(latency.avg),
+                ms(latency.max),
+                ms(latency.mdev)
+            );
+        }
+    }
+}
+
+/// The `p`th percentile of sorted `times`, by the nearest-rank method.
+pub(crate) fn percentile(times: &[Duration], p: f64) -> Duration {
+    if times.is_empty() {
+        return Duration::default();
+    }
+    let rank = (p / 100.0 * times.len() as f64).ceil() as usize;
+    times[rank.clamp(1, times.len()) - 1]
+}
+
+/// Log the average CPU utilisation of the client and the server over all runs.
+pub(crate) fn log_cpu(local: &[CpuUsage], remote: &[CpuUsage]) {
+    if let Some(cpu) = CpuUsage::average(local) {
+        info!("CPU utilisation avg: local {}", cpu);
+    }
+    if let Some(cpu) = CpuUsage::average(remote) {
+        info!("CPU utilisation avg: remote {}", cpu);
+    }
+}
+
+/// Log the average of the last TCP_INFO samples of every run.
+pub(crate) fn log_tcp_info(samples: &[TcpInfo]) {
+    if let Some(info) = TcpInfo::average(samples) {
+        info!("TCP_INFO avg: {}", info);
+    }
+}
+
+#[test]
+fn latency() {
+    use crate::Probe;
+    use std::net::SocketAddr;
+    let target: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+    let results: Vec<Measurement> = [10, 20, 30]
+        .iter()
+        .map(|&ms| Measurement::Time(Probe::new(target, 0, 64, Duration::from_millis(ms))))
+        .collect();
+    let stats = Stats::new(&results, 1);
+    assert_eq!(stats.sent, 4);
+    assert_eq!(stats.loss(), 25.0);
+    let latency = stats.latency.unwrap();
+    assert_eq!(latency.min, Duration::from_millis(10));
+    assert_eq!(latency.avg, Duration::from_millis(20));
+    assert_eq!(latency.max, Duration::from_millis(30));
+    assert!(stats.speed.is_none());
+}
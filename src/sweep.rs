@@ -0,0 +1,243 @@
+use crate::asynctask::{AsyncPingTask, AsyncTask};
+use crate::config::TestConfig;
+use crate::error::{Context, Error, Result};
+use crate::stats::Stats;
+use log::info;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Host bits of the largest CIDR range, so a typo like /8 doesn't start millions of pings.
+const MAX_HOST_BITS: u32 = 16;
+
+/// Result of pinging one target of a sweep.
+#[derive(Debug)]
+pub struct SweepResult {
+    pub target: String,
+    pub stats: Stats,
+    /// Last error, the only result if the target couldn't be pinged at all.
+    pub error: Option<Error>,
+}
+
+/// Ping many targets concurrently, like fping. The pings run on one async-std
+/// executor, `concurrency` only limits how many are in flight.
+pub struct Sweep {
+    targets: Arc<Vec<String>>,
+    config: TestConfig,
+    count: usize,
+    interval: Duration,
+    concurrency: usize,
+}
+
+impl Sweep {
+    pub fn new(targets: Vec<String>, config: &TestConfig) -> Sweep {
+        Sweep {
+            targets: Arc::new(targets),
+            config: config.clone(),
+            count: 5,
+            interval: Duration::from_secs(1),
+            concurrency: 64,
+        }
+    }
+
+    /// Echo requests sent to every target.
+    pub fn count(mut self, count: usize) -> Sweep {
+        self.count = count;
+        self
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Sweep {
+        self.interval = interval;
+        self
+    }
+
+    /// Targets pinged at the same time.
+    pub fn concurrency(mut self, concurrency: usize) -> Sweep {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Hosts of `spec`: an address or hostname as it is, or every host
+    /// address of a CIDR range like `192.0.2.0/24`.
+    pub fn expand(spec: &str) -> Result<Vec<String>> {
+        let mut parts = spec.splitn(2, '/');
+        let (addr, prefix) = match (parts.next(), parts.next()) {
+            (Some(addr), Some(prefix)) => (addr, prefix),
+            _ => return Ok(vec![spec.to_string()]),
+        };
+        let invalid = || Error::InvalidInput(format!("Invalid CIDR range {}", spec));
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let prefix: u32 = prefix.parse().map_err(|_| invalid())?;
+        let (bits, start) = match addr {
+            IpAddr::V4(ip) => (32, u128::from(u32::from(ip))),
+            IpAddr::V6(ip) => (128, u128::from(ip)),
+        };
+        if prefix > bits {
+            return Err(invalid());
+        }
+        let host_bits = bits - prefix;
+        if host_bits > MAX_HOST_BITS {
+            return Err(Error::InvalidInput(format!(
+                "CIDR range {} is too large, at most /{} for IPv4 and /{} for IPv6",
+                spec,
+                32 - MAX_HOST_BITS,
+                128 - MAX_HOST_BITS
+            )));
+        }
+        let size = 1u128 << host_bits;
+        let start = start & !(size - 1);
+        // Network and broadcast addresses of IPv4 ranges don't answer.
+        let (first, last) = if addr.is_ipv4() && size > 2 {
+            (start + 1, start + size - 2)
+        } else {
+            (start, start + size - 1)
+        };
+        Ok((first..=last)
+            .map(|n| match addr {
+                IpAddr::V4(_) => Ipv4Addr::from(n as u32).to_string(),
+                IpAddr::V6(_) => Ipv6Addr::from(n).to_string(),
+            })
+            .collect())
+    }
+
+    /// Targets of a file, separated by whitespace or lines. `#` starts a comment.
+    pub fn read_targets(path: &Path) -> Result<Vec<String>> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut targets = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.splitn(2, '#').next().unwrap();
+            for spec in line.split_whitespace() {
+                targets.extend(
+                    Sweep::expand(spec).with_context(|| format!("{}:{}", path.display(), i + 1))?,
+                );
+            }
+        }
+        Ok(targets)
+    }
+
+    /// Statistics of every target, in the order of the targets.
+    pub fn run(&self) -> Vec<SweepResult> {
+        let next = Arc::new(AtomicUsize::new(0));
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let workers: Vec<_> = (0..self.concurrency.min(self.targets.len()))
+            .map(|_| {
+                let (next, results) = (Arc::clone(&next), Arc::clone(&results));
+                let targets = Arc::clone(&self.targets);
+                let config = self.config.clone();
+                let (count, interval) = (self.count, self.interval);
+                async_std::task::spawn(async move {
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let target = match targets.get(i) {
+                            Some(target) => target,
+                            None => break,
+                        };
+                        let result = ping(target, &config, count, interval).await;
+                        results.lock().unwrap().push((i, result));
+                    }
+                })
+            })
+            .collect();
+        async_std::task::block_on(async {
+            for worker in workers {
+                worker.await;
+            }
+        });
+        let mut results = Arc::try_unwrap(results)
+            .expect("Workers are done")
+            .into_inner()
+            .unwrap();
+        results.sort_by_key(|r| r.0);
+        results.into_iter().map(|r| r.1).collect()
+    }
+
+    /// Log a table of `results`, like the summary of fping.
+    pub fn log_table(results: &[SweepResult]) {
+        let width = results
+            .iter()
+            .map(|r| r.target.len())
+            .max()
+            .unwrap_or(0)
+            .max(6);
+        let ms = |t: Duration| format!("{:.3}", t.as_micros() as f64 / 1000.0);
+        info!("--- statistics ---");
+        info!(
+            "{:<w$}  {:>4} {:>4} {:>7}  {:>9} {:>9} {:>9}",
+            "target",
+            "xmt",
+            "rcv",
+            "loss",
+            "min",
+            "avg",
+            "max",
+            w = width
+        );
+        for r in results {
+            let s = &r.stats;
+            let times = match (&s.latency, &r.error) {
+                (Some(l), _) => format!("{:>9} {:>9} {:>9}", ms(l.min), ms(l.avg), ms(l.max)),
+                (None, Some(e)) => format!("{:>9} {}", "-", e),
+                (None, None) => format!("{:>9}", "-"),
+            };
+            info!(
+                "{:<w$}  {:>4} {:>4} {:>6.2}%  {}",
+                r.target,
+                s.sent,
+                s.received,
+                s.loss(),
+                times,
+                w = width
+            );
+        }
+        let alive = results.iter().filter(|r| r.stats.received > 0).count();
+        info!("{} of {} targets are reachable", alive, results.len());
+    }
+}
+
+async fn ping(target: &str, config: &TestConfig, count: usize, interval: Duration) -> SweepResult {
+    let mut task = match AsyncPingTask::new(target, config).await {
+        Ok(task) => task,
+        Err(e) => {
+            return SweepResult {
+                target: target.to_string(),
+                stats: Stats::new(&Vec::new(), count),
+                error: Some(e),
+            }
+        }
+    };
+    let mut results = Vec::with_capacity(count);
+    let mut error = None;
+    for i in 0..count {
+        if i > 0 {
+            async_std::task::sleep(interval).await;
+        }
+        match task.run().await {
+            Ok(r) => results.push(r),
+            Err(e) => error = Some(e),
+        }
+    }
+    SweepResult {
+        target: target.to_string(),
+        stats: Stats::new(&results, count - results.len()),
+        error,
+    }
+}
+
+#[test]
+fn expand() {
+    assert_eq!(Sweep::expand("example.com").unwrap(), vec!["example.com"]);
+    assert_eq!(
+        Sweep::expand("192.0.2.5/30").unwrap(),
+        vec!["192.0.2.5", "192.0.2.6"]
+    );
+    assert_eq!(Sweep::expand("192.0.2.0/24").unwrap().len(), 254);
+    assert_eq!(
+        Sweep::expand("2001:db8::/127").unwrap(),
+        vec!["2001:db8::", "2001:db8::1"]
+    );
+    assert!(Sweep::expand("192.0.2.0/33").is_err());
+    assert!(Sweep::expand("10.0.0.0/8").is_err());
+}
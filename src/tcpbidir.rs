@@ -0,0 +1,83 @@
+use crate::asynctask::{AsyncTask, Threaded};
+use crate::config::TestConfig;
+use crate::socket::TcpOptions;
+use crate::{Measurement, Result, Task, TcpdownloadTask, TcpuploadTask, Transfer};
+use log::info;
+use std::time::Duration;
+
+/// Download and upload at the same time over two connections.
+pub struct TcpbidirTask {
+    download: Threaded<TcpdownloadTask>,
+    upload: Threaded<TcpuploadTask>,
+    /// Speed of download, upload and both together of every run, in Mbps.
+    speeds: Vec<(f64, f64, f64)>,
+}
+
+impl TcpbidirTask {
+    pub fn new(server: &str, config: &TestConfig, options: &TcpOptions) -> Result<TcpbidirTask> {
+        info!("TCP bidirectional test, connecting to {}", server);
+        Ok(TcpbidirTask {
+            download: Threaded::new(TcpdownloadTask::new(server, config, options)?),
+            upload: Threaded::new(TcpuploadTask::new(server, config, options, false)?),
+            speeds: Vec::new(),
+        })
+    }
+}
+
+fn speed(measurement: &Measurement) -> (usize, Duration) {
+    match measurement {
+        Measurement::Speed(transfer) => (transfer.bytes, transfer.time),
+        Measurement::Time(probe) => (0, probe.time),
+    }
+}
+
+fn mbps(len: usize, time: Duration) -> f64 {
+    len as f64 / (time.as_micros() as f64) * 8.0
+}
+
+impl Task for TcpbidirTask {
+    fn run(&mut self) -> Result<Measurement> {
+        // Both runs start right away, awaiting one doesn't hold back the other.
+        let download = self.download.run();
+        let upload = self.upload.run();
+        let (down, up) = async_std::task::block_on(async { (download.await, upload.await) });
+        let down = down?;
+        let (down_len, down_time) = speed(&down);
+        let (up_len, up_time) = speed(&up?);
+        // Both directions only overlap until the faster one finishes.
+        let len = down_len + up_len;
+        let time = down_time.max(up_time);
+        let speeds = (
+            mbps(down_len, down_time),
+            mbps(up_len, up_time),
+            mbps(len, time),
+        );
+        info!(
+            "Download: {:.3} Mbps, upload: {:.3} Mbps, total: {:.3} Mbps, upload/download: {:.2}",
+            speeds.0,
+            speeds.1,
+            speeds.2,
+            speeds.1 / speeds.0
+        );
+        self.speeds.push(speeds);
+        Ok(Measurement::Speed(Transfer::new(down.target(), len, time)))
+    }
+
+    fn summary(&self) {
+        info!("Download:");
+        self.download.summary();
+        info!("Upload:");
+        self.upload.summary();
+        if self.speeds.is_empty() {
+            return;
+        }
+        let n = self.speeds.len() as f64;
+        let down = self.speeds.iter().map(|s| s.0).sum::<f64>() / n;
+        let up = self.speeds.iter().map(|s| s.1).sum::<f64>() / n;
+        let total = self.speeds.iter().map(|s| s.2).sum::<f64>() / n;
+        info!(
+            "Avg download: {:.3} Mbps, upload: {:.3} Mbps, total: {:.3} Mbps",
+            down, up, total
+        );
+    }
+}
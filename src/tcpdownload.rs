@@ -1,43 +1,68 @@
 use super::MB;
-use crate::{Measurement, Task};
-use anyhow::{anyhow, Context, Result};
+use crate::config::TestConfig;
+use crate::cpu::{CpuTime, CpuUsage};
+use crate::error::{Error, Result};
+use crate::socket::{self, TcpOptions};
+use crate::stats;
+use crate::tcpinfo::TcpInfo;
+use crate::{Measurement, Task, Transfer};
 use log::info;
 use std::io::{BufRead, BufReader, Write};
-use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
-use std::time::{Duration, Instant};
+use std::net::SocketAddr;
+use std::time::Instant;
 
 pub struct TcpdownloadTask {
     addr: SocketAddr,
-    timeout: Duration,
+    config: TestConfig,
+    options: TcpOptions,
     size: usize,
+    /// The server's TCP_INFO at the end of every run, the sender knows where it's limited.
+    tcp_info: Vec<TcpInfo>,
+    /// CPU utilisation of the client and the server in every run.
+    cpu: Vec<CpuUsage>,
+    server_cpu: Vec<CpuUsage>,
 }
 
 impl TcpdownloadTask {
-    pub fn new(server: &str, size: usize, timeout: u64) -> Result<TcpdownloadTask> {
+    pub fn new(server: &str, config: &TestConfig, options: &TcpOptions) -> Result<TcpdownloadTask> {
         info!("TCP download test, connecting to {}", server);
         Ok(TcpdownloadTask {
-            addr: server
-                .to_socket_addrs()
-                .context("Can't resolve IP address")?
-                .next()
-                .ok_or(anyhow!("Don't have IP address"))?,
-            timeout: Duration::from_secs(timeout),
-            size,
+            addr: socket::resolve(server, config)?,
+            config: config.clone(),
+            options: options.clone(),
+            size: config.size,
+            tcp_info: Vec::new(),
+            cpu: Vec::new(),
+            server_cpu: Vec::new(),
         })
     }
 }
 
 impl Task for TcpdownloadTask {
     fn run(&mut self) -> Result<Measurement> {
-        let mut stream = TcpStream::connect_timeout(&self.addr, self.timeout)?;
-        stream.set_read_timeout(Some(self.timeout))?;
-        stream.set_write_timeout(Some(self.timeout))?;
+        let mut stream = socket::tcp_connect(&self.addr, &self.config, &self.options)?;
+        stream.set_read_timeout(Some(self.config.timeout))?;
+        stream.set_write_timeout(Some(self.config.timeout))?;
+        if let Some(name) = &self.options.congestion {
+            // Congestion control of the sender, that is the server, matters here.
+            stream.write_all(format!("CONGESTION {}\r\n", name).as_bytes())?;
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line)?;
+            if !line.starts_with("OK") {
+                return Err(Error::Protocol(format!(
+                    "Server can't use congestion control {}: {}",
+                    name,
+                    line.trim()
+                )));
+            }
+        }
         info!("Download {} MiB from {}", self.size, self.addr);
         let dlstring = format!("DOWNLOAD {}\r\n", self.size * MB);
         stream.write_all(dlstring.as_bytes())?;
         const MIN_PACKET_SIZE: usize = MB;
         let step = (self.size * MB / 32).max(MIN_PACKET_SIZE);
         let mut reader = BufReader::with_capacity(step / 2, stream);
+        let cpu = CpuTime::now();
         let now = Instant::now();
         let mut old = now;
         let mut len = 0;
@@ -55,6 +80,13 @@ impl Task for TcpdownloadTask {
                     time as f64 / 1000.0,
                     len_since_last_measure as f64 / (time as f64) * 8.0
                 );
+                // The receiver only estimates RTT, the server reports the rest at the end.
+                if let Ok(info) = TcpInfo::get(reader.get_ref()) {
+                    info!(
+                        "TCP_INFO: local rcv_rtt={:.3} ms",
+                        info.rcv_rtt.as_micros() as f64 / 1000.0
+                    );
+                }
                 old = Instant::now();
                 old_len = len;
             }
@@ -64,8 +96,30 @@ impl Task for TcpdownloadTask {
             reader.consume(length);
         }
         let time = now.elapsed();
+        let cpu = cpu.usage();
+        info!("CPU utilisation: local {}", cpu);
+        self.cpu.push(cpu);
+        reader.consume(reader.buffer().len());
+        // Servers without the CPU command reply ERROR, that only costs the report.
+        // Servers that can sample TCP_INFO of the download append it to the reply.
+        reader.get_mut().write_all(b"CPU\r\n")?;
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        if let Some(cpu) = CpuUsage::from_reply(&line) {
+            info!("CPU utilisation: remote {}", cpu);
+            self.server_cpu.push(cpu);
+        }
+        if let Some(info) = TcpInfo::from_reply(&line) {
+            info!("TCP_INFO: remote {}", info);
+            self.tcp_info.push(info);
+        }
         stream = reader.into_inner();
         let _ = stream.write_all(b"QUIT\r\n");
-        Ok(Measurement::Speed(len, time))
+        Ok(Measurement::Speed(Transfer::new(self.addr, len, time)))
+    }
+
+    fn summary(&self) {
+        stats::log_tcp_info(&self.tcp_info);
+        stats::log_cpu(&self.cpu, &self.server_cpu);
     }
 }
@@ -0,0 +1,248 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+/// The part of the kernel's `struct tcp_info` that tells where a transfer is limited.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TcpInfo {
+    pub rtt: Duration,
+    pub rttvar: Duration,
+    /// Receiver side estimation of RTT.
+    pub rcv_rtt: Duration,
+    /// Congestion window in segments.
+    pub snd_cwnd: u32,
+    pub total_retrans: u32,
+    /// Bytes per second.
+    pub pacing_rate: u64,
+    /// Bytes per second.
+    pub delivery_rate: u64,
+    /// Time spent sending data.
+    pub busy_time: Duration,
+    /// Time limited by the receive window of the peer.
+    pub rwnd_limited: Duration,
+    /// Time limited by the send buffer.
+    pub sndbuf_limited: Duration,
+}
+
+#[cfg(target_os = "linux")]
+impl TcpInfo {
+    pub fn get<S: std::os::unix::io::AsRawFd>(socket: &S) -> io::Result<TcpInfo> {
+        use std::mem::{size_of, MaybeUninit};
+        /// `struct tcp_info` from `linux/tcp.h` up to `tcpi_sndbuf_limited` (Linux 4.10).
+        /// Older kernels fill less and leave the rest zeroed.
+        #[repr(C)]
+        #[allow(dead_code)]
+        struct RawTcpInfo {
+            state: u8,
+            ca_state: u8,
+            retransmits: u8,
+            probes: u8,
+            backoff: u8,
+            options: u8,
+            wscale: u8,
+            app_limited: u8,
+            rto: u32,
+            ato: u32,
+            snd_mss: u32,
+            rcv_mss: u32,
+            unacked: u32,
+            sacked: u32,
+            lost: u32,
+            retrans: u32,
+            fackets: u32,
+            last_data_sent: u32,
+            last_ack_sent: u32,
+            last_data_recv: u32,
+            last_ack_recv: u32,
+            pmtu: u32,
+            rcv_ssthresh: u32,
+            rtt: u32,
+            rttvar: u32,
+            snd_ssthresh: u32,
+            snd_cwnd: u32,
+            advmss: u32,
+            reordering: u32,
+            rcv_rtt: u32,
+            rcv_space: u32,
+            total_retrans: u32,
+            pacing_rate: u64,
+            max_pacing_rate: u64,
+            bytes_acked: u64,
+            bytes_received: u64,
+            segs_out: u32,
+            segs_in: u32,
+            notsent_bytes: u32,
+            min_rtt: u32,
+            data_segs_in: u32,
+            data_segs_out: u32,
+            delivery_rate: u64,
+            busy_time: u64,
+            rwnd_limited: u64,
+            sndbuf_limited: u64,
+        }
+        let mut raw = MaybeUninit::<RawTcpInfo>::zeroed();
+        let mut len = size_of::<RawTcpInfo>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                raw.as_mut_ptr() as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe: zeroed memory is a valid RawTcpInfo, the kernel only fills it.
+        let raw = unsafe { raw.assume_init() };
+        Ok(TcpInfo {
+            rtt: Duration::from_micros(raw.rtt.into()),
+            rttvar: Duration::from_micros(raw.rttvar.into()),
+            rcv_rtt: Duration::from_micros(raw.rcv_rtt.into()),
+            snd_cwnd: raw.snd_cwnd,
+            total_retrans: raw.total_retrans,
+            pacing_rate: raw.pacing_rate,
+            delivery_rate: raw.delivery_rate,
+            busy_time: Duration::from_micros(raw.busy_time),
+            rwnd_limited: Duration::from_micros(raw.rwnd_limited),
+            sndbuf_limited: Duration::from_micros(raw.sndbuf_limited),
+        })
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl TcpInfo {
+    pub fn get<S>(_socket: &S) -> io::Result<TcpInfo> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "TCP_INFO is only supported on Linux",
+        ))
+    }
+}
+
+impl TcpInfo {
+    /// Share of busy time limited by the peer's receive window, in percent.
+    pub fn rwnd_limited_percent(&self) -> f64 {
+        percent(self.rwnd_limited, self.busy_time)
+    }
+
+    /// Share of busy time limited by the local send buffer, in percent.
+    pub fn sndbuf_limited_percent(&self) -> f64 {
+        percent(self.sndbuf_limited, self.busy_time)
+    }
+}
+
+fn percent(part: Duration, total: Duration) -> f64 {
+    if total.as_micros() == 0 {
+        0.0
+    } else {
+        100.0 * part.as_micros() as f64 / total.as_micros() as f64
+    }
+}
+
+impl fmt::Display for TcpInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = |t: Duration| t.as_micros() as f64 / 1000.0;
+        let mbps = |rate: u64| rate as f64 * 8.0 / 1_000_000.0;
+        write!(
+            f,
+            "rtt={:.3}/{:.3} ms rcv_rtt={:.3} ms cwnd={} retrans={} pacing={:.3} Mbps \
+             delivery={:.3} Mbps rwnd_limited={:.1}% sndbuf_limited={:.1}%",
+            ms(self.rtt),
+            ms(self.rttvar),
+            ms(self.rcv_rtt),
+            self.snd_cwnd,
+            self.total_retrans,
+            mbps(self.pacing_rate),
+            mbps(self.delivery_rate),
+            self.rwnd_limited_percent(),
+            self.sndbuf_limited_percent()
+        )
+    }
+}
+
+impl TcpInfo {
+    /// The `TCPINFO <fields>` part of a server reply, times in microseconds.
+    pub fn reply(&self) -> String {
+        format!(
+            "TCPINFO {} {} {} {} {} {} {} {} {} {}",
+            self.rtt.as_micros(),
+            self.rttvar.as_micros(),
+            self.rcv_rtt.as_micros(),
+            self.snd_cwnd,
+            self.total_retrans,
+            self.pacing_rate,
+            self.delivery_rate,
+            self.busy_time.as_micros(),
+            self.rwnd_limited.as_micros(),
+            self.sndbuf_limited.as_micros()
+        )
+    }
+
+    /// Parse the `TCPINFO <fields>` part of a server reply.
+    pub fn from_reply(line: &str) -> Option<TcpInfo> {
+        let fields = line
+            .split_whitespace()
+            .skip_while(|&w| w != "TCPINFO")
+            .skip(1)
+            .take(10)
+            .map(|w| w.parse().ok())
+            .collect::<Option<Vec<u64>>>()?;
+        if fields.len() != 10 {
+            return None;
+        }
+        let us = |i: usize| Duration::from_micros(fields[i]);
+        Some(TcpInfo {
+            rtt: us(0),
+            rttvar: us(1),
+            rcv_rtt: us(2),
+            snd_cwnd: u32::try_from(fields[3]).ok()?,
+            total_retrans: u32::try_from(fields[4]).ok()?,
+            pacing_rate: fields[5],
+            delivery_rate: fields[6],
+            busy_time: us(7),
+            rwnd_limited: us(8),
+            sndbuf_limited: us(9),
+        })
+    }
+
+    /// Average of the last samples of every run, `None` without any.
+    /// Retransmissions and times are summed up, so are the shares of busy time.
+    pub(crate) fn average(samples: &[TcpInfo]) -> Option<TcpInfo> {
+        if samples.is_empty() {
+            return None;
+        }
+        let n = samples.len() as u32;
+        let avg = |f: fn(&TcpInfo) -> Duration| samples.iter().map(f).sum::<Duration>() / n;
+        Some(TcpInfo {
+            rtt: avg(|i| i.rtt),
+            rttvar: avg(|i| i.rttvar),
+            rcv_rtt: avg(|i| i.rcv_rtt),
+            snd_cwnd: samples.iter().map(|i| i.snd_cwnd).sum::<u32>() / n,
+            total_retrans: samples.iter().map(|i| i.total_retrans).sum(),
+            pacing_rate: samples.iter().map(|i| i.pacing_rate).sum::<u64>() / n as u64,
+            delivery_rate: samples.iter().map(|i| i.delivery_rate).sum::<u64>() / n as u64,
+            busy_time: samples.iter().map(|i| i.busy_time).sum(),
+            rwnd_limited: samples.iter().map(|i| i.rwnd_limited).sum(),
+            sndbuf_limited: samples.iter().map(|i| i.sndbuf_limited).sum(),
+        })
+    }
+}
+
+#[test]
+fn from_reply() {
+    let info = TcpInfo {
+        rtt: Duration::from_micros(1500),
+        snd_cwnd: 10,
+        pacing_rate: 125_000_000,
+        busy_time: Duration::from_millis(900),
+        rwnd_limited: Duration::from_millis(300),
+        ..TcpInfo::default()
+    };
+    let line = format!("CPU 12.5 30.0 41.2 {}\n", info.reply());
+    assert_eq!(TcpInfo::from_reply(&line), Some(info));
+    assert!(TcpInfo::from_reply("CPU 12.5 30.0 41.2\n").is_none());
+    assert!(TcpInfo::from_reply("TCPINFO 1 2 3").is_none());
+}
@@ -1,32 +1,30 @@
-use crate::{Measurement, Task};
-use anyhow::{anyhow, Context, Result};
+use crate::config::TestConfig;
+use crate::socket::{self, TcpOptions};
+use crate::{Measurement, Probe, Result, Task};
 use log::info;
-use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
-use std::time::{Duration, Instant};
+use std::net::SocketAddr;
+use std::time::Instant;
 
 pub struct TcpingTask {
     target: SocketAddr,
-    timeout: Duration,
+    config: TestConfig,
+    options: TcpOptions,
     seq: u16,
 }
 
 impl TcpingTask {
-    pub fn new(addr: &str, timeout: u64) -> Result<TcpingTask> {
-        let target = addr
-            .to_socket_addrs()
-            .context("Can't resolve IP address")?
-            .next()
-            .ok_or(anyhow!("Don't have IP address"))?;
+    pub fn new(addr: &str, config: &TestConfig, options: &TcpOptions) -> Result<TcpingTask> {
+        let target = socket::resolve(addr, config)?;
         let format_target = target.to_string();
         if format_target != addr {
             info!("Ping to {} ({}) using TCP", addr, format_target);
         } else {
             info!("Ping to {} using TCP", format_target);
         }
-        let timeout = Duration::from_secs(timeout);
         Ok(Self {
             target,
-            timeout,
+            config: config.clone(),
+            options: options.clone(),
             seq: 0,
         })
     }
@@ -34,9 +32,9 @@ impl TcpingTask {
 
 impl Task for TcpingTask {
     fn run(&mut self) -> Result<Measurement> {
-        self.seq += 1;
+        self.seq = self.seq.wrapping_add(1);
         let start = Instant::now();
-        let tcp = TcpStream::connect_timeout(&self.target, self.timeout)?;
+        let tcp = socket::tcp_connect(&self.target, &self.config, &self.options)?;
         let time = start.elapsed();
         tcp.shutdown(std::net::Shutdown::Both)?;
         drop(tcp);
@@ -44,6 +42,11 @@ impl Task for TcpingTask {
             "Connected to {}: seq={} time={:?}",
             self.target, self.seq, time
         );
-        Ok(Measurement::Time(time))
+        Ok(Measurement::Time(Probe::new(
+            self.target,
+            self.seq as u64,
+            0,
+            time,
+        )))
     }
 }
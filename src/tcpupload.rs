@@ -1,58 +1,112 @@
 use super::MB;
-use crate::{Measurement, Task};
-use anyhow::{anyhow, Context, Result};
+use crate::config::{Pattern, TestConfig};
+use crate::cpu::{CpuTime, CpuUsage};
+use crate::error::{Context, Error, Result};
+use crate::socket::{self, TcpOptions};
+use crate::stats;
+use crate::tcpinfo::TcpInfo;
+use crate::zerocopy::RandPool;
+use crate::{Measurement, Task, Transfer};
 use log::info;
 use rand::{Rng, SeedableRng};
 use rand_xoshiro::Xoshiro256Plus;
 use std::io::{BufRead, BufReader, Write};
-use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
-use std::time::{Duration, Instant};
+use std::net::SocketAddr;
+use std::time::Instant;
+
+/// Size of the memfd the zero-copy path sends from.
+const ZEROCOPY_POOL: usize = 64 * MB;
+/// Largest amount of data handed to one sendfile(2) call.
+const ZEROCOPY_CHUNK: usize = 16 * MB;
 
 pub struct TcpuploadTask {
     addr: SocketAddr,
-    timeout: Duration,
+    config: TestConfig,
+    options: TcpOptions,
     size: usize,
+    rand_pool: Vec<u8>,
+    /// Send the payload with sendfile(2) from this pool instead of `rand_pool`.
+    zerocopy: Option<RandPool>,
+    /// The last TCP_INFO of every run.
+    tcp_info: Vec<TcpInfo>,
+    /// CPU utilisation of the client and the server in every run.
+    cpu: Vec<CpuUsage>,
+    server_cpu: Vec<CpuUsage>,
 }
 
 impl TcpuploadTask {
-    pub fn new(server: &str, size: usize, timeout: u64) -> Result<TcpuploadTask> {
+    pub fn new(
+        server: &str,
+        config: &TestConfig,
+        options: &TcpOptions,
+        zerocopy: bool,
+    ) -> Result<TcpuploadTask> {
         info!("TCP upload test, connecting to {}", server);
+        // The server reads the upload as one line.
+        if let Pattern::Byte(b'\r') | Pattern::Byte(b'\n') = config.pattern {
+            return Err(Error::InvalidInput(
+                "Upload payload can't be line breaks".into(),
+            ));
+        }
+        let mut rand_pool = vec![0u8; 2 * MB];
+        config.pattern.fill(&mut rand_pool);
+        let zerocopy = if zerocopy {
+            Some(
+                RandPool::new(ZEROCOPY_POOL, |chunk| config.pattern.fill(chunk))
+                    .context("Failed to create zero-copy pool")?,
+            )
+        } else {
+            None
+        };
         Ok(TcpuploadTask {
-            addr: server
-                .to_socket_addrs()
-                .context("Can't resolve IP address")?
-                .next()
-                .ok_or(anyhow!("Don't have IP address"))?,
-            timeout: Duration::from_secs(timeout),
-            size,
+            addr: socket::resolve(server, config)?,
+            config: config.clone(),
+            options: options.clone(),
+            size: config.size,
+            rand_pool,
+            zerocopy,
+            tcp_info: Vec::new(),
+            cpu: Vec::new(),
+            server_cpu: Vec::new(),
         })
     }
 }
 
 impl Task for TcpuploadTask {
     fn run(&mut self) -> Result<Measurement> {
-        let mut stream = TcpStream::connect_timeout(&self.addr, self.timeout)?;
-        stream.set_read_timeout(Some(self.timeout))?;
-        stream.set_write_timeout(Some(self.timeout))?;
+        let mut stream = socket::tcp_connect(&self.addr, &self.config, &self.options)?;
+        stream.set_read_timeout(Some(self.config.timeout))?;
+        stream.set_write_timeout(Some(self.config.timeout))?;
         info!("Upload {} MiB to {}", self.size, self.addr);
-        let mut rand_pool = Vec::with_capacity(2 * MB);
-        let mut rng = Xoshiro256Plus::from_entropy();
-        for _ in 0..2 * MB {
-            rand_pool.push(rng.gen_range(0x20, 0x7F));
+        if let Ok(name) = socket::congestion(&stream) {
+            info!("Congestion control: {}", name);
         }
+        let mut rng = Xoshiro256Plus::from_entropy();
         let size = self.size * MB;
         let ulstring = format!("UPLOAD {} 0\r\n", size);
         stream.write_all(ulstring.as_bytes())?;
         let mut rand_size = size - ulstring.len() - 1; // \r\n count into 1 byte, wierd
         let mut line = String::new();
+        let cpu = CpuTime::now();
         let now = Instant::now();
         let mut old = now;
         let mut old_size = rand_size;
         let step = (size / 32).max(MB);
+        let mut offset = 0;
         while rand_size > 0 {
-            let start = rng.gen_range(0, MB);
-            let len = rand_size.min(MB);
-            stream.write_all(&rand_pool[start..start + len])?;
+            let len = match &self.zerocopy {
+                Some(pool) => {
+                    let len = pool.send(&stream, offset, rand_size.min(ZEROCOPY_CHUNK))?;
+                    offset = (offset + len) % pool.len();
+                    len
+                }
+                None => {
+                    let start = rng.gen_range(0, MB);
+                    let len = rand_size.min(MB);
+                    stream.write_all(&self.rand_pool[start..start + len])?;
+                    len
+                }
+            };
             rand_size -= len;
             let len_since_last_measure = old_size - rand_size;
             if len_since_last_measure >= step {
@@ -63,6 +117,9 @@ impl Task for TcpuploadTask {
                     time as f64 / 1000.0,
                     len_since_last_measure as f64 / (time as f64) * 8.0
                 );
+                if let Ok(info) = TcpInfo::get(&stream) {
+                    info!("TCP_INFO: {}", info);
+                }
                 old = Instant::now();
                 old_size = rand_size;
             }
@@ -71,8 +128,24 @@ impl Task for TcpuploadTask {
         let mut reader = BufReader::new(stream);
         reader.read_line(&mut line)?;
         let time = now.elapsed();
+        let cpu = cpu.usage();
+        info!("CPU utilisation: local {}", cpu);
+        self.cpu.push(cpu);
+        if let Some(cpu) = CpuUsage::from_reply(&line) {
+            info!("CPU utilisation: remote {}", cpu);
+            self.server_cpu.push(cpu);
+        }
         stream = reader.into_inner();
+        if let Ok(info) = TcpInfo::get(&stream) {
+            info!("TCP_INFO: {}", info);
+            self.tcp_info.push(info);
+        }
         let _ = stream.write_all(b"QUIT\r\n");
-        Ok(Measurement::Speed(size, time))
+        Ok(Measurement::Speed(Transfer::new(self.addr, size, time)))
+    }
+
+    fn summary(&self) {
+        stats::log_tcp_info(&self.tcp_info);
+        stats::log_cpu(&self.cpu, &self.server_cpu);
     }
 }
@@ -0,0 +1,213 @@
+use super::MB;
+use crate::config::{Pattern, TestConfig};
+use crate::error::{Error, Result};
+use crate::socket;
+use crate::wire::{self, DATA, DATA_LEN, FIN, REPORT, REPORT_LEN};
+use crate::{Measurement, Task, Transfer};
+use log::info;
+use rand::{Rng, SeedableRng};
+use rand_xoshiro::Xoshiro256Plus;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Times FIN is sent before giving up on the final report.
+const FIN_RETRIES: u32 = 3;
+
+/// Receiver statistics reported by the server.
+#[derive(Clone, Copy, Debug, Default)]
+struct Report {
+    last: bool,
+    packets: u64,
+    bytes: u64,
+    lost: u64,
+    duplicated: u64,
+    reordered: u64,
+    jitter: Duration,
+    time: Duration,
+}
+
+impl Report {
+    fn parse(buf: &[u8]) -> Option<Report> {
+        if buf.len() < REPORT_LEN || wire::kind(buf) != Some(REPORT) {
+            return None;
+        }
+        // The fields follow the last flag byte.
+        let v = |i: usize| wire::u64_at(buf, wire::field(i) + 1).unwrap();
+        Some(Report {
+            last: buf[wire::HEADER_LEN] != 0,
+            packets: v(0),
+            bytes: v(1),
+            lost: v(2),
+            duplicated: v(3),
+            reordered: v(4),
+            jitter: Duration::from_micros(v(5)),
+            time: Duration::from_micros(v(6)),
+        })
+    }
+
+    fn log(&self, name: &str) {
+        let total = self.packets + self.lost;
+        info!(
+            "{}: {:.3} MiB, {:.3} Mbps, lost {}/{} ({:.2}%), {} duplicated, {} reordered, jitter {:.3} ms",
+            name,
+            self.bytes as f64 / MB as f64,
+            self.bytes as f64 * 8.0 / self.time.as_micros().max(1) as f64,
+            self.lost,
+            total,
+            100.0 * self.lost as f64 / total.max(1) as f64,
+            self.duplicated,
+            self.reordered,
+            self.jitter.as_micros() as f64 / 1000.0
+        );
+    }
+}
+
+/// Send UDP datagrams at a constant bitrate, the nettest server reports what arrived.
+pub struct UdploadTask {
+    target: SocketAddr,
+    socket: UdpSocket,
+    timeout: Duration,
+    /// Bits per second.
+    bitrate: f64,
+    length: usize,
+    pattern: Pattern,
+    duration: Duration,
+    /// Final report of every run.
+    reports: Vec<Report>,
+}
+
+impl UdploadTask {
+    /// Send `length` byte datagrams at `bitrate` Mbps for `duration`.
+    pub fn new(
+        addr: &str,
+        config: &TestConfig,
+        bitrate: f64,
+        length: usize,
+        duration: Duration,
+    ) -> Result<UdploadTask> {
+        let target = socket::resolve(addr, config)?;
+        if length < DATA_LEN {
+            return Err(Error::InvalidInput(format!(
+                "Datagrams must be at least {} bytes",
+                DATA_LEN
+            )));
+        }
+        if bitrate <= 0.0 {
+            return Err(Error::InvalidInput("Bitrate must be positive".into()));
+        }
+        let socket = socket::udp_connect(&target, config)?;
+        info!(
+            "UDP load test to {}, {} byte datagrams at {:.3} Mbps for {:?}",
+            target, length, bitrate, duration
+        );
+        Ok(Self {
+            target,
+            socket,
+            timeout: config.timeout,
+            bitrate: bitrate * 1_000_000.0,
+            length,
+            pattern: config.pattern,
+            duration,
+            reports: Vec::new(),
+        })
+    }
+
+    /// Log the interval reports that arrived, returns the final one if any.
+    fn receive(&self, buf: &mut [u8]) -> Result<Option<Report>> {
+        loop {
+            match self.socket.recv(buf) {
+                Ok(len) => match Report::parse(&buf[..len]) {
+                    Some(report) if report.last => return Ok(Some(report)),
+                    Some(report) => report.log("Interval"),
+                    None => {}
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl Task for UdploadTask {
+    fn run(&mut self) -> Result<Measurement> {
+        let id: u64 = Xoshiro256Plus::from_entropy().gen();
+        let mut packet = wire::packet(DATA, &[id, 0, 0]);
+        packet.resize(self.length, 0);
+        self.pattern.fill(&mut packet[DATA_LEN..]);
+        let mut buf = vec![0u8; 1500];
+        // Seconds between datagrams.
+        let gap = self.length as f64 * 8.0 / self.bitrate;
+        self.socket.set_nonblocking(true)?;
+        let started = SystemTime::now();
+        let start = Instant::now();
+        let mut seq: u64 = 0;
+        while start.elapsed() < self.duration {
+            let due = Duration::from_secs_f64(gap * seq as f64);
+            if let Some(wait) = due.checked_sub(start.elapsed()) {
+                thread::sleep(wait);
+            }
+            wire::set_u64(&mut packet, wire::field(1), seq);
+            let sent = start.elapsed().as_micros() as u64;
+            wire::set_u64(&mut packet, wire::field(2), sent);
+            match self.socket.send(&packet) {
+                Ok(_) => seq += 1,
+                // Socket buffer is full, try again.
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e.into()),
+            }
+            self.receive(&mut buf)?;
+        }
+        let send_time = start.elapsed();
+        self.socket.set_nonblocking(false)?;
+        info!(
+            "Sent {} datagrams, {:.3} Mbps",
+            seq,
+            (seq * self.length as u64) as f64 * 8.0 / send_time.as_micros() as f64
+        );
+        let fin = wire::packet(FIN, &[id, seq]);
+        // A zero read timeout is rejected, as tiny timeouts divide to.
+        let fin_timeout = (self.timeout / FIN_RETRIES).max(Duration::from_millis(1));
+        self.socket.set_read_timeout(Some(fin_timeout))?;
+        let mut report = None;
+        for _ in 0..FIN_RETRIES {
+            self.socket.send(&fin)?;
+            report = self.receive(&mut buf)?;
+            if report.is_some() {
+                break;
+            }
+        }
+        self.socket.set_read_timeout(Some(self.timeout))?;
+        // Not even one of the FINs was answered.
+        let report = report.ok_or(Error::Timeout)?;
+        report.log("Received");
+        self.reports.push(report);
+        Ok(Measurement::Speed(Transfer {
+            target: self.target,
+            bytes: report.bytes as usize,
+            start: started,
+            time: report.time,
+        }))
+    }
+
+    fn summary(&self) {
+        if self.reports.is_empty() {
+            return;
+        }
+        let n = self.reports.len() as u32;
+        let total = Report {
+            last: true,
+            packets: self.reports.iter().map(|r| r.packets).sum(),
+            bytes: self.reports.iter().map(|r| r.bytes).sum(),
+            lost: self.reports.iter().map(|r| r.lost).sum(),
+            duplicated: self.reports.iter().map(|r| r.duplicated).sum(),
+            reordered: self.reports.iter().map(|r| r.reordered).sum(),
+            jitter: self.reports.iter().map(|r| r.jitter).sum::<Duration>() / n,
+            time: self.reports.iter().map(|r| r.time).sum(),
+        };
+        total.log("Total");
+    }
+}
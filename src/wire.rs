@@ -0,0 +1,103 @@
+// Wire format of the nettest UDP packets and of the TCP load stream, spoken by the server too.
+use std::convert::TryInto;
+
+/// Every nettest UDP packet starts with this and a type byte, followed by the
+/// big-endian u64 fields of the type. QUIC packets always have the fixed bit
+/// 0x40 set in the first byte, so both share one port.
+pub const MAGIC: &[u8] = b"\0NT";
+/// Sequence-numbered load packet: test ID, seq, send time in microseconds, padding.
+pub const DATA: u8 = b'D';
+/// End of a load test: test ID, number of packets sent.
+pub const FIN: u8 = b'F';
+/// Echoed back as it is: test ID, seq, frame, send time in microseconds, padding.
+pub const ECHO: u8 = b'E';
+/// Echoed back with whether the TOS byte it arrived with is known, and the
+/// TOS byte, one byte each: probe ID, seq.
+pub const MARK: u8 = b'M';
+/// Receiver statistics of a load test: last flag byte, then packets, bytes,
+/// lost, duplicated, reordered, jitter and time in microseconds.
+pub const REPORT: u8 = b'R';
+
+/// Magic and type byte.
+pub const HEADER_LEN: usize = 4;
+pub const DATA_LEN: usize = field(3);
+pub const ECHO_LEN: usize = field(4);
+pub const MARK_LEN: usize = field(2);
+pub const REPORT_LEN: usize = HEADER_LEN + 1 + 7 * 8;
+
+/// Offset of the `i`th u64 field.
+pub const fn field(i: usize) -> usize {
+    HEADER_LEN + i * 8
+}
+
+pub fn is_nettest(pkt: &[u8]) -> bool {
+    pkt.starts_with(MAGIC)
+}
+
+/// Type byte of a nettest packet.
+pub fn kind(pkt: &[u8]) -> Option<u8> {
+    if is_nettest(pkt) {
+        pkt.get(MAGIC.len()).copied()
+    } else {
+        None
+    }
+}
+
+/// Packet of type `kind` with `fields`, more can be appended.
+pub fn packet(kind: u8, fields: &[u64]) -> Vec<u8> {
+    let mut pkt = Vec::with_capacity(field(fields.len()));
+    pkt.extend_from_slice(MAGIC);
+    pkt.push(kind);
+    for v in fields {
+        pkt.extend_from_slice(&v.to_be_bytes());
+    }
+    pkt
+}
+
+/// Big-endian u64 at `offset`, `None` if the packet is too short.
+pub fn u64_at(pkt: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(
+        pkt.get(offset..offset + 8)?.try_into().unwrap(),
+    ))
+}
+
+pub fn set_u64(pkt: &mut [u8], offset: usize, value: u64) {
+    pkt[offset..offset + 8].copy_from_slice(&value.to_be_bytes());
+}
+
+/// Frame of bulk data in the reply to the TCP LOAD command.
+pub const LOAD_DATA: u8 = b'D';
+/// Frame of the small object requested by SMALL while the load runs.
+pub const LOAD_SMALL: u8 = b'S';
+/// Type byte and big-endian u32 payload length.
+pub const FRAME_HEADER_LEN: usize = 5;
+
+pub fn frame_header(kind: u8, len: usize) -> [u8; FRAME_HEADER_LEN] {
+    let mut header = [kind; FRAME_HEADER_LEN];
+    header[1..].copy_from_slice(&(len as u32).to_be_bytes());
+    header
+}
+
+/// Type byte and payload length of a frame of the load stream.
+pub fn parse_frame_header(header: &[u8; FRAME_HEADER_LEN]) -> (u8, usize) {
+    let len = u32::from_be_bytes(header[1..].try_into().unwrap());
+    (header[0], len as usize)
+}
+
+#[test]
+fn fields() {
+    let mut pkt = packet(DATA, &[7, 1, 2]);
+    assert_eq!(pkt.len(), DATA_LEN);
+    assert_eq!(kind(&pkt), Some(DATA));
+    set_u64(&mut pkt, field(1), 9);
+    assert_eq!(u64_at(&pkt, field(0)), Some(7));
+    assert_eq!(u64_at(&pkt, field(1)), Some(9));
+    assert_eq!(u64_at(&pkt, field(3)), None);
+    assert_eq!(kind(b"\x40quic"), None);
+}
+
+#[test]
+fn frames() {
+    let header = frame_header(LOAD_SMALL, 1024);
+    assert_eq!(parse_frame_header(&header), (LOAD_SMALL, 1024));
+}
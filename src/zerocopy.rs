@@ -0,0 +1,72 @@
+// Zero-copy sends from a memfd, for the client and the server.
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+/// Payload bytes in a memfd, sent to sockets with sendfile(2)
+/// so the payload never gets copied through user space.
+pub struct RandPool {
+    file: File,
+    len: usize,
+}
+
+#[cfg(target_os = "linux")]
+impl RandPool {
+    /// Pool of `len` bytes, filled chunk by chunk with `fill`.
+    pub fn new<F: FnMut(&mut [u8])>(len: usize, mut fill: F) -> io::Result<RandPool> {
+        let fd =
+            unsafe { libc::memfd_create(b"nettest\0".as_ptr() as *const _, libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        let mut chunk = vec![0u8; 1024 * 1024];
+        let mut written = 0;
+        while written < len {
+            let n = chunk.len().min(len - written);
+            fill(&mut chunk[..n]);
+            file.write_all(&chunk[..n])?;
+            written += n;
+        }
+        Ok(RandPool { file, len })
+    }
+
+    /// Send up to `max` bytes starting at `offset` of the pool with one sendfile(2) call,
+    /// returns bytes sent. A full nonblocking socket and an expired SO_SNDTIMEO
+    /// of a blocking one are both `WouldBlock`.
+    pub fn send<S: AsRawFd>(&self, socket: &S, offset: usize, max: usize) -> io::Result<usize> {
+        let mut off = offset as libc::off_t;
+        let count = max.min(self.len - offset);
+        let ret =
+            unsafe { libc::sendfile(socket.as_raw_fd(), self.file.as_raw_fd(), &mut off, count) };
+        match ret {
+            n if n < 0 => Err(io::Error::last_os_error()),
+            0 if count > 0 => Err(io::ErrorKind::WriteZero.into()),
+            n => Ok(n as usize),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl RandPool {
+    pub fn new<F: FnMut(&mut [u8])>(_len: usize, _fill: F) -> io::Result<RandPool> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Zero-copy send is only supported on Linux",
+        ))
+    }
+
+    pub fn send<S: AsRawFd>(&self, _socket: &S, _offset: usize, _max: usize) -> io::Result<usize> {
+        unreachable!("RandPool can't be created")
+    }
+}
+
+impl RandPool {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}